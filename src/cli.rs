@@ -0,0 +1,286 @@
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use crate::{
+    config::MyConfig,
+    midi_format::base::MidiChannel,
+    mixer::MixTarget,
+    player::{PlayOptions, MAX_SPEED, MIN_SPEED},
+    recorder::RecordOptions,
+    render::RenderOptions,
+    wav::WavFormat,
+};
+
+pub const USAGE: &str = "用法: piano_demo [选项] <命令> [参数]
+
+命令:
+  play <file>                  播放MIDI文件
+      --speed <百分比>         播放速度 50-150 默认100 不改变音高
+      --loop <N-M>             循环播放第N到第M小节
+      --ramp <百分比>          每完整循环一次加快多少 最多加到原速
+      --mute <t0,c10,...>      静音音轨(t 从0开始 和info一致)或者通道(c 1-16)
+      --solo <t0,c10,...>      独奏音轨或者通道
+  live [file]                  用MIDI键盘实时演奏 指定文件时同时播放文件 可以用play的选项
+                               键盘换到文件没有用到的通道 不受文件的静音和跳转影响
+  record <out.mid>             实时演奏并录音 按回车停止后保存
+      --bpm <n>                录音的速度 默认120
+      --ppq <n>                每个四分音符的tick数 1-32767 默认480
+      --smf-format <0|1>       0: 单音轨 1: 速度和演奏分开两个音轨 默认0
+  render <file> -o <out.wav>   离线渲染成WAV文件
+      --format <16|24|f32>     采样格式 默认16
+      --tail <秒>              最后一个事件之后继续渲染的时间 默认2
+  info <file>                  显示MIDI文件的信息
+  list-ports                   列出MIDI输入端口
+  list-devices                 列出音频输出设备
+
+选项:
+  --soundfont <path>           音色库 默认sf2/TimGM6mb.sf2
+  --sample-rate <hz>           采样率 默认48000
+  --buffer-size <frames>       声卡缓冲区大小 默认使用设备的设置
+  --device <name>              音频输出设备名 默认使用系统默认设备
+  --port <编号|名字>           MIDI输入端口 默认只有一个端口时直接使用 多个时询问
+  --channel-map <L,R>          多声道设备上左右声道输出到哪两个通道 默认1,2
+  --bend-range <半音>          弯音范围 0-24 默认2
+  --lenient                    宽松解析 跳过MIDI文件里损坏的部分并打印警告
+  -h, --help                   显示这段帮助";
+
+pub enum Command {
+    Play {
+        file: PathBuf,
+        options: PlayOptions,
+    },
+    Live {
+        file: Option<PathBuf>,
+        options: PlayOptions,
+    },
+    Record {
+        file: PathBuf,
+        options: RecordOptions,
+    },
+    Render {
+        file: PathBuf,
+        output: PathBuf,
+        options: RenderOptions,
+    },
+    Info {
+        file: PathBuf,
+    },
+    ListPorts,
+    ListDevices,
+    Help,
+}
+
+// 解析命令行参数(不包含程序名) 选项可以出现在任何位置
+pub fn parse_args<I>(args: I) -> Result<(Command, MyConfig), Box<dyn Error>>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut config = MyConfig::default();
+    let mut render_options = RenderOptions::default();
+    let mut record_options = RecordOptions::default();
+    let mut play_options = PlayOptions::default();
+    let mut output: Option<PathBuf> = None;
+    let mut positional: Vec<String> = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("选项 {arg} 缺少参数"));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok((Command::Help, config)),
+            "--soundfont" => config.sf2_path = value()?,
+            "--sample-rate" => config.sample_rate = value()?.parse()?,
+            "--buffer-size" => config.buffer_size = Some(value()?.parse()?),
+            "--device" => config.output_device = Some(value()?),
+            "--port" => config.midi_port = Some(value()?),
+            "--lenient" => config.lenient = true,
+            "--channel-map" => config.channel_map = parse_channel_map(&value()?)?,
+            "--bend-range" => {
+                let semitones: u8 = value()?.parse()?;
+                if semitones > 24 {
+                    return Err(format!("弯音范围要在0到24个半音之间: {semitones}").into());
+                }
+                config.pitch_bend_range = semitones;
+            }
+            "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+            "--format" => {
+                render_options.format = match value()?.as_str() {
+                    "16" => WavFormat::Pcm16,
+                    "24" => WavFormat::Pcm24,
+                    "f32" | "32" => WavFormat::Float32,
+                    format => return Err(format!("不支持的采样格式: {format}").into()),
+                }
+            }
+            "--tail" => {
+                let tail: f64 = value()?.parse()?;
+                render_options.tail = Duration::try_from_secs_f64(tail)
+                    .map_err(|_| format!("错误的渲染尾部时间: {tail}"))?;
+            }
+            "--bpm" => {
+                let bpm: f64 = value()?.parse()?;
+                // 速度事件只有3个字节 每个四分音符的微秒数最多0xFFFFFF
+                let tempo = (60_000_000f64 / bpm).round();
+                if !(1f64..=0xFFFFFF as f64).contains(&tempo) {
+                    return Err(format!("错误的速度: {bpm}").into());
+                }
+                record_options.tempo = tempo as u32;
+            }
+            "--speed" => {
+                let speed = value()?.parse::<f64>()? / 100f64;
+                if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+                    return Err(format!("播放速度要在50到150之间: {}", speed * 100f64).into());
+                }
+                play_options.speed = speed;
+            }
+            "--loop" => play_options.loop_bars = Some(parse_bars(&value()?)?),
+            "--ramp" => play_options.ramp_step = value()?.parse::<f64>()?.max(0f64) / 100f64,
+            "--mute" => play_options.muted.extend(parse_mix_targets(&value()?)?),
+            "--solo" => play_options.solo.extend(parse_mix_targets(&value()?)?),
+            "--ppq" => {
+                let ppq: u16 = value()?.parse()?;
+                // 最高位是1时会被当成SMPTE时间分辨率
+                if !(1..=0x7FFF).contains(&ppq) {
+                    return Err(format!("每个四分音符的tick数要在1到32767之间: {ppq}").into());
+                }
+                record_options.ppq = ppq;
+            }
+            "--smf-format" => {
+                record_options.format = match value()?.as_str() {
+                    "0" => 0,
+                    "1" => 1,
+                    format => return Err(format!("不支持的MIDI格式: {format}").into()),
+                }
+            }
+            _ if arg.starts_with('-') => return Err(format!("未知的选项: {arg}").into()),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match positional.next().as_deref() {
+        None => return Err("缺少命令".into()),
+        Some("play") => Command::Play {
+            file: file_arg(positional.next())?,
+            options: play_options,
+        },
+        Some("live") => Command::Live {
+            file: positional.next().map(PathBuf::from),
+            options: play_options,
+        },
+        Some("record") => Command::Record {
+            file: PathBuf::from(positional.next().ok_or("缺少输出文件路径")?),
+            options: record_options,
+        },
+        Some("render") => Command::Render {
+            file: file_arg(positional.next())?,
+            output: output.ok_or("render 需要用 -o 指定输出文件")?,
+            options: render_options,
+        },
+        Some("info") => Command::Info {
+            file: file_arg(positional.next())?,
+        },
+        Some("list-ports") => Command::ListPorts,
+        Some("list-devices") => Command::ListDevices,
+        Some(command) => return Err(format!("未知的命令: {command}").into()),
+    };
+    if let Some(arg) = positional.next() {
+        return Err(format!("多余的参数: {arg}").into());
+    }
+    Ok((command, config))
+}
+
+// 小节范围 N-M 只写一个数时只循环这一小节
+pub fn parse_bars(bars: &str) -> Result<(u32, u32), Box<dyn Error>> {
+    let (start, end) = bars.split_once('-').unwrap_or((bars, bars));
+    let (start, end): (u32, u32) = (start.trim().parse()?, end.trim().parse()?);
+    if start == 0 || end < start {
+        return Err(format!("错误的小节范围: {bars}").into());
+    }
+    Ok((start, end))
+}
+
+// 逗号分开的静音/独奏对象 t<音轨>或者c<通道>
+pub fn parse_mix_targets(targets: &str) -> Result<Vec<MixTarget>, Box<dyn Error>> {
+    targets.split(',').map(parse_mix_target).collect()
+}
+
+pub fn parse_mix_target(target: &str) -> Result<MixTarget, Box<dyn Error>> {
+    let target = target.trim();
+    let error = || format!("错误的音轨或者通道: {target} (例如 t1 c10)");
+    if let Some(index) = target.strip_prefix('t') {
+        return Ok(MixTarget::Track {
+            index: index.parse().map_err(|_| error())?,
+        });
+    }
+    let channel = target
+        .strip_prefix('c')
+        .and_then(|channel| channel.parse::<u8>().ok())
+        .and_then(|channel| MidiChannel::new(channel.wrapping_sub(1)))
+        .ok_or_else(error)?;
+    Ok(MixTarget::Channel { channel })
+}
+
+// 输出通道 L,R 从1开始
+fn parse_channel_map(channel_map: &str) -> Result<(usize, usize), Box<dyn Error>> {
+    let error = || format!("错误的通道映射: {channel_map} (例如 3,4)");
+    let (left, right) = channel_map.split_once(',').ok_or_else(error)?;
+    let (left, right): (usize, usize) = (
+        left.trim().parse().map_err(|_| error())?,
+        right.trim().parse().map_err(|_| error())?,
+    );
+    if left == 0 || right == 0 {
+        return Err(error().into());
+    }
+    Ok((left - 1, right - 1))
+}
+
+fn file_arg(arg: Option<String>) -> Result<PathBuf, Box<dyn Error>> {
+    Ok(PathBuf::from(arg.ok_or("缺少MIDI文件路径")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Command, MyConfig), Box<dyn Error>> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn tail() {
+        let Ok((Command::Render { options, .. }, _)) =
+            parse(&["render", "a.mid", "-o", "a.wav", "--tail", "0.5"])
+        else {
+            panic!("应该解析成render命令");
+        };
+        assert_eq!(options.tail, Duration::from_millis(500));
+        for tail in ["-1", "NaN", "inf"] {
+            assert!(parse(&["render", "a.mid", "-o", "a.wav", "--tail", tail]).is_err());
+        }
+    }
+
+    #[test]
+    fn bpm() {
+        let tempo = |bpm: &str| match parse(&["record", "a.mid", "--bpm", bpm]) {
+            Ok((Command::Record { options, .. }, _)) => Some(options.tempo),
+            _ => None,
+        };
+        assert_eq!(tempo("120"), Some(500_000));
+        assert_eq!(tempo("3.6"), Some(16_666_667));
+        // 0xFFFFFF微秒大约是3.58BPM
+        for bpm in ["3.5", "0", "-120", "NaN", "1e9"] {
+            assert_eq!(tempo(bpm), None, "{bpm}");
+        }
+    }
+
+    #[test]
+    fn bend_range() {
+        let bend_range = |semitones: &str| {
+            parse(&["list-ports", "--bend-range", semitones])
+                .ok()
+                .map(|(_, config)| config.pitch_bend_range)
+        };
+        assert_eq!(bend_range("0"), Some(0));
+        assert_eq!(bend_range("12"), Some(12));
+        assert_eq!(bend_range("24"), Some(24));
+        assert_eq!(bend_range("25"), None);
+        assert_eq!(bend_range("-1"), None);
+    }
+}
@@ -1,11 +1,50 @@
+use std::{ops::Deref, sync::OnceLock};
+
 pub struct MyConfig {
     pub sample_rate: u32,
     pub channels_count: u32,
     pub channel_sample_count: u32,
+    pub pitch_bend_range: u8, // 弯音范围(半音) 通过RPN 0设置到每个通道
+    pub sf2_path: String,
+    pub buffer_size: Option<u32>,      // 声卡的缓冲区大小 None时使用设备默认值
+    pub output_device: Option<String>, // 输出设备名 None时使用默认设备
+    pub midi_port: Option<String>,     // MIDI输入端口的编号或者名字 None时交互选择
+    pub lenient: bool,                 // 宽松解析 尽量读取损坏的MIDI文件
+    pub channel_map: (usize, usize),   // 多声道设备上左右声道输出的通道 从0开始
+}
+
+impl Default for MyConfig {
+    fn default() -> Self {
+        MyConfig {
+            sample_rate: 48000,
+            channels_count: 2,
+            channel_sample_count: 480,
+            pitch_bend_range: 2,
+            sf2_path: "sf2/TimGM6mb.sf2".to_string(),
+            buffer_size: None,
+            output_device: None,
+            midi_port: None,
+            lenient: false,
+            channel_map: (0, 1),
+        }
+    }
+}
+
+// 启动时由命令行参数设置一次 之后只读
+pub struct GlobalConfig(OnceLock<MyConfig>);
+
+impl GlobalConfig {
+    pub fn set(&self, config: MyConfig) -> Result<(), MyConfig> {
+        self.0.set(config)
+    }
+}
+
+impl Deref for GlobalConfig {
+    type Target = MyConfig;
+
+    fn deref(&self) -> &MyConfig {
+        self.0.get_or_init(MyConfig::default)
+    }
 }
 
-pub static CONFIG: MyConfig = MyConfig {
-    sample_rate: 48000,
-    channels_count: 2,
-    channel_sample_count: 480,
-};
\ No newline at end of file
+pub static CONFIG: GlobalConfig = GlobalConfig(OnceLock::new());
@@ -0,0 +1,75 @@
+use std::io::{stdout, Write};
+
+use crate::midi_format::karaoke::Karaoke;
+
+const HIGHLIGHT: &str = "\x1b[1;33m"; // 已经唱过的部分 黄色加粗
+const RESET: &str = "\x1b[0m";
+const CLEAR_LINE: &str = "\r\x1b[2K";
+
+// 播放时在终端里显示当前这一行歌词 唱到的部分高亮
+pub struct LyricsDisplay<'a> {
+    m_karaoke: &'a Karaoke,
+    m_position: Option<(usize, usize)>,
+}
+
+impl<'a> LyricsDisplay<'a> {
+    pub fn new(karaoke: &'a Karaoke) -> LyricsDisplay<'a> {
+        let tags = &karaoke.m_tags;
+        for title in tags.m_title.iter() {
+            println!("{title}");
+        }
+        for info in tags.m_info.iter() {
+            println!("{info}");
+        }
+        if !tags.m_title.is_empty() || !tags.m_info.is_empty() {
+            println!();
+        }
+        LyricsDisplay {
+            m_karaoke: karaoke,
+            m_position: None,
+        }
+    }
+
+    // 播放到tick时调用 位置没变就什么都不做
+    pub fn update(&mut self, tick: u64) {
+        let position = self.m_karaoke.position_at(tick);
+        if position == self.m_position {
+            return;
+        }
+        let Some((line_index, sung)) = position else {
+            return;
+        };
+        let line = &self.m_karaoke.m_lines[line_index];
+        let pre_line = self.m_position.map(|(pre_line, _)| pre_line);
+        if pre_line != Some(line_index) {
+            // 换行时先把上一行整行高亮后留在屏幕上
+            // 新的一段前面空一行
+            if let Some(pre_line) = pre_line {
+                let pre_line = &self.m_karaoke.m_lines[pre_line];
+                println!("{CLEAR_LINE}{HIGHLIGHT}{}{RESET}", pre_line.text());
+                if line.m_new_paragraph {
+                    println!();
+                }
+            }
+        }
+        let (sung_text, rest_text): (String, String) = (
+            line.m_syllables[..sung]
+                .iter()
+                .map(|syllable| syllable.m_text.as_str())
+                .collect(),
+            line.m_syllables[sung..]
+                .iter()
+                .map(|syllable| syllable.m_text.as_str())
+                .collect(),
+        );
+        print!("{CLEAR_LINE}{HIGHLIGHT}{sung_text}{RESET}{rest_text}");
+        let _ = stdout().flush();
+        self.m_position = position;
+    }
+
+    pub fn finish(&self) {
+        if self.m_position.is_some() {
+            println!();
+        }
+    }
+}
@@ -7,118 +7,383 @@ use cpal::{
     traits::{DeviceTrait, StreamTrait},
     Device, FromSample, SizedSample, StreamConfig,
 };
-use midi_format::MidiFile;
+use midi_format::{MidiFile, ParseOptions};
 use midir::{MidiInput, MidiInputConnection, MidiInputPort};
 use rustysynth::Synthesizer;
 use std::{
     error::Error,
+    path::Path,
+    process::exit,
     sync::{Arc, Mutex},
     thread::sleep,
+    time::Duration,
 };
 
 use crate::{
-    midi_derive::init_midi_derive,
+    cli::{parse_args, parse_mix_target, Command, USAGE},
+    lyrics::LyricsDisplay,
+    midi_derive::{init_midi_derive, list_midi_ports},
     midi_format::{
         base::*,
-        midi_message::{Event, MessageEvent, MidiMessage},
+        karaoke::Karaoke,
+        midi_message::{Event, MessageEvent, MetaEvent, MidiMessage, SysExAssembler},
+        sequencer::merge_tracks,
+        tempo_map::TempoMap,
     },
-    output_derive::init_output_derive,
-    synthesizers::init_synthesizers,
+    output_derive::{init_output_derive, list_output_devices},
+    player::{live_channel, PlayOptions, Player, PlayerHandle, MAX_SPEED, MIN_SPEED},
+    recorder::{RecordOptions, Recorder},
+    render::{render_to_wav, RenderOptions},
+    scheduler::SharedScheduler,
+    synthesizers::{init_synthesizers, process_event, SharedSynthesizer},
 };
+mod cli;
 mod config;
+mod lyrics;
 mod midi_derive;
 mod midi_format;
+mod mixer;
 mod output_derive;
+mod player;
+mod recorder;
+mod render;
+mod scheduler;
 mod synthesizers;
+mod wav;
 fn main() {
-    // midi_format::test();
-    play_midi();
+    let (command, config) = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            exit(2);
+        }
+    };
+    let _ = CONFIG.set(config);
+    let result = match command {
+        Command::Play { file, options } => play_midi(&file, &options),
+        Command::Live { file, options } => run(file.as_deref(), &options),
+        Command::Record { file, options } => record_midi(&file, &options),
+        Command::Render {
+            file,
+            output,
+            options,
+        } => render_midi(&file, &output, &options),
+        Command::Info { file } => print_info(&file),
+        Command::ListPorts => list_midi_ports(),
+        Command::ListDevices => list_output_devices(),
+        Command::Help => {
+            println!("{USAGE}");
+            Ok(())
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("错误: {err}");
+        exit(1);
+    }
 }
 
-fn play_midi() {
-    let (mut synthesizer, out_put_derive) = init_conn().unwrap();
-    let _output_conn = bind_synthesizer_to_output::<f32>(&mut synthesizer, &out_put_derive);
-    _output_conn.play().unwrap();
-    let midi_file = MidiFile::parse(include_bytes!("../test_assets/sanye.mid")).unwrap();
-    let trackdiv = midi_file.header.m_time_division; // 一个四分音符的时间
-    let one_tick: u32 = 545455 / 480; // TODO: 计算出来
-    for track in midi_file.tracks.0.iter() {
-        for message in track.m_midi_message.iter() {
-            let event: &Event = &message.m_ment_event;
-            let mut sum_tick: u64 = 0;
-
-            for (index, ti) in message.m_delta_time.iter().rev().enumerate() {
-                let _ti = (MidiInt::data & *ti).bits() as u32;
-                let bi = _ti * 128u32.pow(index as u32) as u32;
-                sum_tick += bi as u64;
-            }
+// 按配置选择严格或者宽松解析 宽松模式下的警告打印到stderr
+fn load_midi(path: &Path) -> Result<MidiFile, Box<dyn Error>> {
+    let options = if CONFIG.lenient {
+        ParseOptions::lenient()
+    } else {
+        ParseOptions::default()
+    };
+    let (midi_file, warnings) = MidiFile::parse_with_options(&std::fs::read(path)?, &options)?;
+    for warning in warnings {
+        eprintln!("警告: {warning}");
+    }
+    Ok(midi_file)
+}
+
+fn play_midi(path: &Path, options: &PlayOptions) -> Result<(), Box<dyn Error>> {
+    let midi_file = load_midi(path)?;
+    let (mut synthesizer, out_put_derive) = init_conn(midi_file.embedded_soundfont.as_deref())?;
+    let scheduler = SharedScheduler::default();
+    let _output_conn = bind_synthesizer_to_output(&mut synthesizer, &scheduler, &out_put_derive)?;
+    _output_conn.play()?;
+    play_file(&midi_file, synthesizer, scheduler, options)
+}
 
-            let _r: u64 = sum_tick * one_tick as u64;
-            let delay_time = time::Duration::from_micros(_r);
-            sleep(delay_time);
-            println!("delay_time: {_r}ms  event: {event:?}");
-            match event {
-                Event::Midi {
-                    message: message_event,
-                } => match message_event {
-                    // TODO: 还有一些事件需要实现
-                    MessageEvent::NoteOn { key, velocity } => synthesizer.lock().unwrap().note_on(
-                        0,
-                        key.bits() as i32,
-                        velocity.bits() as i32,
-                    ),
-                    MessageEvent::NoteOff { key, velocity } => {
-                        synthesizer.lock().unwrap().note_off(0, key.bits() as i32)
+// 在当前线程播放文件 同时从标准输入接收控制命令 播完或者收到q时返回
+fn play_file(
+    midi_file: &MidiFile,
+    synthesizer: SharedSynthesizer,
+    scheduler: SharedScheduler,
+    options: &PlayOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut player = Player::new(midi_file, synthesizer, scheduler, options)?;
+    let bar_map = player.bar_map().clone();
+    spawn_player_input(player.handle());
+    // 有歌词时显示歌词 不再打印每个事件
+    let karaoke = Karaoke::new(midi_file);
+    let mut lyrics_display = (!karaoke.is_empty()).then(|| LyricsDisplay::new(&karaoke));
+    player.run(|tick, midi_message| match &mut lyrics_display {
+        Some(lyrics_display) => lyrics_display.update(tick),
+        None => {
+            let (bar, beat) = bar_map.tick_to_bar(tick);
+            println!(
+                "{bar}:{beat} tick: {tick}  event: {:?}",
+                midi_message.m_ment_event
+            )
+        }
+    });
+    if let Some(lyrics_display) = &lyrics_display {
+        lyrics_display.finish();
+    }
+    Ok(())
+}
+
+// 从标准输入读取播放控制命令 一行一个
+fn spawn_player_input(handle: PlayerHandle) {
+    println!("p: 暂停 c: 继续 s: 停止 q: 退出 t <秒>: 跳到时间 k <tick>: 跳到tick");
+    println!(
+        "b <小节> [拍]: 跳到小节 v <百分比>: 播放速度 l <N> <M>: 循环第N到第M小节 l: 取消循环"
+    );
+    println!("m <t音轨|c通道>: 切换静音 o <t音轨|c通道>: 切换独奏");
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let mut words = line.split_whitespace();
+            let command = words.next();
+            // 静音和独奏的参数是t0 c10这样的对象
+            if let Some(toggle @ ("m" | "o")) = command {
+                match words.next().map(parse_mix_target) {
+                    Some(Ok(target)) => {
+                        let (name, on) = match toggle {
+                            "m" => ("静音", handle.toggle_muted(target)),
+                            _ => ("独奏", handle.toggle_solo(target)),
+                        };
+                        println!("{target} {name}: {}", if on { "开" } else { "关" });
                     }
-                    _ => (),
-                },
-                Event::None => todo!(),
+                    Some(Err(err)) => eprintln!("{err}"),
+                    None => eprintln!("缺少音轨或者通道"),
+                }
+                continue;
+            }
+            // 命令后面的参数都是非负数
+            let arguments: Option<Vec<f64>> = words
+                .map(|word| word.parse::<f64>().ok().filter(|number| *number >= 0f64))
+                .collect();
+            match (command, arguments.as_deref()) {
+                (Some("p"), _) => handle.pause(),
+                (Some("c"), _) => handle.play(),
+                (Some("s"), _) => handle.stop(),
+                (Some("q"), _) => {
+                    handle.quit();
+                    break;
+                }
+                (Some("t"), Some(&[seconds])) => {
+                    handle.seek_time(Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX))
+                }
+                (Some("k"), Some(&[tick])) => handle.seek_tick(tick as u64),
+                (Some("b"), Some(&[bar])) => handle.seek_bar(bar as u32, 1),
+                (Some("b"), Some(&[bar, beat])) => handle.seek_bar(bar as u32, beat as u32),
+                (Some("v"), Some(&[percent]))
+                    if (MIN_SPEED..=MAX_SPEED).contains(&(percent / 100f64)) =>
+                {
+                    handle.set_speed(percent / 100f64)
+                }
+                (Some("l"), Some(&[])) => handle.clear_loop(),
+                (Some("l"), Some(&[bar])) => {
+                    if let Err(err) = handle.set_loop(bar as u32, bar as u32) {
+                        eprintln!("{err}");
+                    }
+                }
+                (Some("l"), Some(&[start_bar, end_bar])) => {
+                    if let Err(err) = handle.set_loop(start_bar as u32, end_bar as u32) {
+                        eprintln!("{err}");
+                    }
+                }
+                (None, _) => {}
+                _ => eprintln!("无法识别的命令: {line}"),
+            }
+        }
+    });
+}
+
+fn render_midi(path: &Path, output: &Path, options: &RenderOptions) -> Result<(), Box<dyn Error>> {
+    let midi_file = load_midi(path)?;
+    let synthesizer = init_synthesizers(midi_file.embedded_soundfont.as_deref())?;
+    render_to_wav(
+        &midi_file,
+        &mut synthesizer.lock().unwrap(),
+        output,
+        options,
+    )?;
+    println!("已渲染到 {}", output.display());
+    Ok(())
+}
+
+fn print_info(path: &Path) -> Result<(), Box<dyn Error>> {
+    let midi_file = load_midi(path)?;
+    let header = &midi_file.header;
+    let tempo_map = TempoMap::new(&midi_file);
+    let end_tick = merge_tracks(&midi_file)
+        .last()
+        .map_or(0, |event| event.m_tick);
+    println!("文件: {}", path.display());
+    println!("格式: {}", header.m_format);
+    println!("音轨数: {}", midi_file.tracks.0.len());
+    println!("时间分辨率: {:?}", header.m_time_division);
+    println!("音符数: {}", midi_file.notes().len());
+    if let Some(sf2) = &midi_file.embedded_soundfont {
+        println!("自带音色库: SF2 ({}字节)", sf2.len());
+    }
+    println!(
+        "时长: {:.3}秒 ({end_tick} tick)",
+        tempo_map.ticks_to_micros(end_tick) as f64 / 1_000_000f64
+    );
+    for (index, track) in midi_file.tracks.0.iter().enumerate() {
+        let name = track
+            .m_midi_message
+            .iter()
+            .find_map(|message| match &message.m_ment_event {
+                Event::Meta {
+                    meta: meta @ MetaEvent::TrackName { .. },
+                } => meta.text(),
+                _ => None,
+            })
+            .unwrap_or_default();
+        println!("音轨{index}: {name} ({}个事件)", track.m_midi_message.len());
+    }
+    for (index, meta) in midi_file.meta_events() {
+        match meta {
+            MetaEvent::SetTempo { tempo } => {
+                println!("音轨{index} 速度: {:.2} BPM", 60_000_000f64 / *tempo as f64)
+            }
+            MetaEvent::TimeSignature { numerator, .. } => println!(
+                "音轨{index} 拍号: {numerator}/{}",
+                meta.denominator().unwrap_or_default()
+            ),
+            MetaEvent::KeySignature { sharps, minor } => println!(
+                "音轨{index} 调号: {sharps} {}",
+                if *minor { "小调" } else { "大调" }
+            ),
+            MetaEvent::Marker { .. } | MetaEvent::CuePoint { .. } => {
+                println!("音轨{index} 标记: {}", meta.text().unwrap_or_default())
             }
+            _ => (),
         }
     }
+    Ok(())
 }
 
-fn init_conn() -> Result<(Arc<Mutex<Synthesizer>>, Device), Box<dyn Error>> {
-    let synthesizer: Arc<Mutex<Synthesizer>> = init_synthesizers()?;
+fn init_conn(embedded_sf2: Option<&[u8]>) -> Result<(SharedSynthesizer, Device), Box<dyn Error>> {
+    let synthesizer: SharedSynthesizer = init_synthesizers(embedded_sf2)?;
     let out_put_derive: Device = init_output_derive()?;
     Ok((synthesizer, out_put_derive))
 }
 
-fn run() -> Result<(), Box<dyn Error>> {
+// 指定文件时键盘和文件通过同一个合成器一起发声 可以把要自己弹的音轨静音
+// 文件播完以后继续实时演奏
+fn run(file: Option<&Path>, options: &PlayOptions) -> Result<(), Box<dyn Error>> {
+    let midi_file = file.map(load_midi).transpose()?;
     let (midi_in, port) = init_midi_derive()?;
-    let mut synthesizer = init_synthesizers()?;
+    let mut synthesizer = init_synthesizers(
+        midi_file
+            .as_ref()
+            .and_then(|midi_file| midi_file.embedded_soundfont.as_deref()),
+    )?;
     let out_put_derive = init_output_derive()?;
+    // 键盘换到文件没有用到的通道 文件的静音和复位不会影响实时演奏
+    let live_channel = midi_file.as_ref().and_then(|midi_file| {
+        let channel = live_channel(&midi_file.channels());
+        match channel {
+            Some(channel) => println!("键盘使用通道{channel}"),
+            None => eprintln!("警告: 文件用到了所有通道 键盘和文件共用通道"),
+        }
+        channel
+    });
 
     // 1. 将midi输入链接到合成器
-    let _midi_conn = bind_midi_to_synthesizer(midi_in, &port, &mut synthesizer);
+    let _midi_conn = bind_midi_to_synthesizer(midi_in, &port, &mut synthesizer, live_channel, None);
     // 2. 将合成器链接到输出设备
-    let _output_conn = bind_synthesizer_to_output::<f32>(&mut synthesizer, &out_put_derive);
+    let scheduler = SharedScheduler::default();
+    let _output_conn = bind_synthesizer_to_output(&mut synthesizer, &scheduler, &out_put_derive)?;
     _output_conn.play()?;
+    if let Some(midi_file) = &midi_file {
+        play_file(midi_file, synthesizer, scheduler, options)?;
+    }
 
-    loop {} // 防止主线程退出
+    loop {
+        std::thread::park(); // 防止主线程退出
+    }
     #[allow(unreachable_code)]
     Ok(())
 }
+
+// 和live一样演奏 同时把输入的消息录下来 按回车停止后写入文件
+fn record_midi(path: &Path, options: &RecordOptions) -> Result<(), Box<dyn Error>> {
+    let (midi_in, port) = init_midi_derive()?;
+    let mut synthesizer = init_synthesizers(None)?;
+    let out_put_derive = init_output_derive()?;
+    let recorder = Arc::new(Mutex::new(Recorder::default()));
+
+    let midi_conn = bind_midi_to_synthesizer(
+        midi_in,
+        &port,
+        &mut synthesizer,
+        None,
+        Some(recorder.clone()),
+    );
+    let _output_conn = bind_synthesizer_to_output(
+        &mut synthesizer,
+        &SharedScheduler::default(),
+        &out_put_derive,
+    )?;
+    _output_conn.play()?;
+
+    println!("正在录音 按回车停止");
+    std::io::stdin().read_line(&mut String::new())?;
+    midi_conn.close();
+
+    let recorder = recorder.lock().unwrap();
+    if recorder.is_empty() {
+        return Err("没有录到任何消息".into());
+    }
+    recorder.to_midi_file(options).save(path)?;
+    println!("已保存到 {}", path.display());
+    Ok(())
+}
+
+// live_channel不为None时 所有通道消息都换到这个通道上
 fn bind_midi_to_synthesizer(
     midi_in: MidiInput,
     port: &MidiInputPort,
     synthesizer: &mut Arc<Mutex<Synthesizer>>,
+    live_channel: Option<MidiChannel>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
 ) -> MidiInputConnection<()> {
     let _synthesizer = synthesizer.clone();
+    let mut sysex_assembler = SysExAssembler::default();
     let _conn: MidiInputConnection<()> = midi_in
         .connect(
             port,
             "midir-read-input",
-            move |_, message, _| {
-                let (channel, key, velocity) =
-                    (message[0] as i32, message[1] as i32, message[2] as i32);
-                // 获取MIDI信号 判断是否按下键
-                match channel {
-                    144 => _synthesizer.lock().unwrap().note_on(0, key, velocity),
-                    128 => _synthesizer.lock().unwrap().note_off(0, key),
-                    _ => (),
-                }
+            move |stamp, message, _| {
                 println!("{:?}", message);
+                let mut midi_message = match MidiMessage::from_live(message) {
+                    Ok(midi_message) => midi_message,
+                    Err(err) => {
+                        eprintln!("无法解析的MIDI消息: {err}");
+                        return;
+                    }
+                };
+                if let Some(channel) = live_channel {
+                    midi_message.set_channel(channel);
+                }
+                // 获取MIDI信号 发送到对应的通道
+                process_event(
+                    &mut _synthesizer.lock().unwrap(),
+                    &midi_message,
+                    &mut sysex_assembler,
+                );
+                if let Some(recorder) = &recorder {
+                    recorder.lock().unwrap().record(stamp, midi_message);
+                }
             },
             (),
         )
@@ -126,39 +391,99 @@ fn bind_midi_to_synthesizer(
     _conn
 }
 
-fn bind_synthesizer_to_output<T>(
+// 文件播放的事件通过scheduler在音频回调里按采样时间发给合成器
+fn bind_synthesizer_to_output(
     synthesizer: &mut Arc<Mutex<Synthesizer>>,
+    scheduler: &SharedScheduler,
     output_device: &Device,
-) -> cpal::Stream
-where
-    T: SizedSample + FromSample<f64>,
-{
+) -> Result<cpal::Stream, Box<dyn Error>> {
     let mut _synthesizer = synthesizer.clone();
-    let config = output_device.default_output_config().unwrap();
+    let scheduler = scheduler.clone();
+    let config = output_device.default_output_config()?;
     dbg!(config.clone());
-    let config: StreamConfig = config.into();
-    let channels = config.channels as usize;
+    let mut config: StreamConfig = config.into();
+    // 输出的采样率要和合成器一致
+    config.sample_rate = cpal::SampleRate(CONFIG.sample_rate);
+    if let Some(buffer_size) = CONFIG.buffer_size {
+        config.buffer_size = cpal::BufferSize::Fixed(buffer_size);
+    }
+    let mut output = OutputBuffer::new(config.channels as usize);
     let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
-    let stream: cpal::Stream = output_device
-        .build_output_stream(
-            &config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                write_data(data, channels, &mut _synthesizer)
-            },
-            err_fn,
-            None,
-        )
-        .unwrap();
-    stream
+    let stream: cpal::Stream = output_device.build_output_stream(
+        &config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            write_data(data, &mut output, &_synthesizer, &scheduler)
+        },
+        err_fn,
+        None,
+    )?;
+    Ok(stream)
+}
+
+// 音频回调里重复使用的缓冲区 创建流的时候分配好 回调里不再分配内存
+struct OutputBuffer {
+    m_channels: usize,
+    m_channel_map: (usize, usize), // 左右声道输出到设备的哪个通道
+    m_left: Vec<f32>,
+    m_right: Vec<f32>,
+}
+
+impl OutputBuffer {
+    // 设备没有配置的通道时退回到前两个通道 单声道设备不使用映射
+    fn new(channels: usize) -> OutputBuffer {
+        let (left, right) = CONFIG.channel_map;
+        let channel_map = if channels > 1 && left.max(right) >= channels {
+            eprintln!(
+                "警告: 设备只有{channels}个通道 无法输出到通道{},{} 使用通道1,2",
+                left + 1,
+                right + 1
+            );
+            (0, 1)
+        } else {
+            (left, right)
+        };
+        let block_size = CONFIG.channel_sample_count as usize;
+        OutputBuffer {
+            m_channels: channels,
+            m_channel_map: channel_map,
+            m_left: vec![0f32; block_size],
+            m_right: vec![0f32; block_size],
+        }
+    }
 }
 
-fn write_data(data: &mut [f32], _channels: usize, synthesizer: &Arc<Mutex<Synthesizer>>) {
+// 正好填满声卡给的data data里是交错排列的m_channels个通道
+// 按缓冲区大小分块渲染 单声道设备输出左右声道的平均值
+// 多声道设备只有映射到的两个通道有声音 左右映射到同一个通道时两边相加
+fn write_data(
+    data: &mut [f32],
+    output: &mut OutputBuffer,
+    synthesizer: &Arc<Mutex<Synthesizer>>,
+    scheduler: &SharedScheduler,
+) {
+    data.fill(0f32);
+    let channels = output.m_channels;
+    if channels == 0 {
+        return;
+    }
+    let (left_channel, right_channel) = output.m_channel_map;
+    // 先锁调度器再锁合成器 播放器不会同时持有两个锁
+    let mut scheduler = scheduler.lock().unwrap();
     let mut synthesizer = synthesizer.lock().unwrap();
-    let mut left: Vec<f32> = vec![0f32; CONFIG.channel_sample_count as usize];
-    let mut right: Vec<f32> = vec![0f32; CONFIG.channel_sample_count as usize];
-    synthesizer.render(&mut left, &mut right);
-    for index in 0..CONFIG.channel_sample_count as usize {
-        data[index * 2] = left[index];
-        data[index * 2 + 1] = right[index];
+    for block in data.chunks_mut(output.m_left.len() * channels) {
+        let frames = block.len() / channels;
+        let (left, right) = (&mut output.m_left[..frames], &mut output.m_right[..frames]);
+        scheduler.render(&mut synthesizer, left, right);
+        for (frame, (left, right)) in block
+            .chunks_exact_mut(channels)
+            .zip(left.iter().zip(right.iter()))
+        {
+            if channels == 1 {
+                frame[0] = (left + right) * 0.5;
+            } else {
+                frame[left_channel] += left;
+                frame[right_channel] += right;
+            }
+        }
     }
 }
@@ -1,39 +1,64 @@
-use midir::{MidiInput, MidiInputPort};
+use midir::{Ignore, MidiInput, MidiInputPort};
 use std::{
     error::Error,
     io::{stdin, stdout, Write},
 };
 
+use crate::config::CONFIG;
+
 pub fn init_midi_derive() -> Result<(MidiInput, MidiInputPort), Box<dyn Error>> {
     let client_name = "输入设备";
-    let midi_in: MidiInput = MidiInput::new(client_name).unwrap();
+    let mut midi_in: MidiInput = MidiInput::new(client_name)?;
+    midi_in.ignore(Ignore::TimeAndActiveSense); // 需要接收sysex
     let ports = midi_in.ports();
-    let port: MidiInputPort = match ports.len() {
-        0 => panic!("未找到任何端口"),
-        1 => ports[0].clone(),
-        _ => match chose_port(&ports, &midi_in) {
-            Some(port) => port,
-            None => panic!("未找到任何端口"),
-        },
+    let port: MidiInputPort = match (&CONFIG.midi_port, ports.len()) {
+        (Some(name), _) => {
+            find_port(&ports, &midi_in, name).ok_or(format!("未找到端口: {name}"))?
+        }
+        (None, 0) => return Err("未找到任何端口".into()),
+        (None, 1) => ports[0].clone(),
+        (None, _) => chose_port(&ports, &midi_in)?,
     };
 
     Ok((midi_in, port))
 }
 
-fn chose_port<'a>(ports: &Vec<MidiInputPort>, mide: &MidiInput) -> Option<MidiInputPort> {
+pub fn list_midi_ports() -> Result<(), Box<dyn Error>> {
+    let midi_in: MidiInput = MidiInput::new("输入设备")?;
+    for (i, port) in midi_in.ports().iter().enumerate() {
+        println!("第{}个: 端口名为 {}", i, midi_in.port_name(port)?);
+    }
+    Ok(())
+}
+
+// 按编号或者名字查找端口 名字只需要包含即可
+fn find_port(ports: &[MidiInputPort], mide: &MidiInput, name: &str) -> Option<MidiInputPort> {
+    if let Ok(index) = name.parse::<usize>() {
+        return ports.get(index).cloned();
+    }
+    ports
+        .iter()
+        .find(|port| {
+            mide.port_name(port)
+                .is_ok_and(|port_name| port_name.contains(name))
+        })
+        .cloned()
+}
+
+fn chose_port(ports: &[MidiInputPort], mide: &MidiInput) -> Result<MidiInputPort, Box<dyn Error>> {
     println!("找到多个端口，请选择一个端口连接：");
     for (i, port) in ports.iter().enumerate() {
-        println!("第{}个: 端口名为 {}", i, mide.port_name(port).unwrap());
+        println!("第{}个: 端口名为 {}", i, mide.port_name(port)?);
     }
     println!("请输入端口编号：");
-    stdout().flush().unwrap();
+    stdout().flush()?;
     let mut input = String::new();
-    stdin().read_line(&mut input).unwrap();
-    let port = ports
-        .get(input.trim().parse::<usize>().unwrap())
-        .ok_or("错误的端口编号");
-    match port {
-        Ok(port) => Some(port.clone()),
-        Err(_) => None,
-    }
+    stdin().read_line(&mut input)?;
+    let input = input.trim();
+    let port = input
+        .parse::<usize>()
+        .ok()
+        .and_then(|index| ports.get(index))
+        .ok_or(format!("错误的端口编号: {input}"))?;
+    Ok(port.clone())
 }
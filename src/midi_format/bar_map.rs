@@ -0,0 +1,190 @@
+use super::{
+    header::TimeDivision,
+    midi_message::{Event, MetaEvent},
+    MidiFile,
+};
+
+// 拍号变化点 从m_tick开始是第m_bar小节(从0开始) 每小节m_beats拍
+#[derive(Debug, Clone, Copy)]
+struct MeterSegment {
+    m_tick: u64,
+    m_bar: u32,
+    m_beats: u32,
+    m_beat_ticks: u64, // 每拍的tick数
+}
+
+// 小节表 用来在tick和小节/拍之间转换 小节和拍都从1开始数
+// 没有TimeSignature事件时按4/4拍计算
+// 拍号在小节中间变化时 从变化的位置开始新的一小节
+#[derive(Debug, Clone)]
+pub struct BarMap {
+    m_segments: Vec<MeterSegment>,
+}
+
+impl BarMap {
+    // 收集所有音轨里的TimeSignature事件
+    pub fn new(midi_file: &MidiFile) -> BarMap {
+        let mut time_signatures: Vec<(u64, u32, u32)> = Vec::new();
+        for track in midi_file.tracks.0.iter() {
+            for (tick, event) in track.events() {
+                if let Event::Meta {
+                    meta: meta @ MetaEvent::TimeSignature { numerator, .. },
+                } = event
+                {
+                    let denominator = meta.denominator().unwrap_or(4);
+                    time_signatures.push((tick, *numerator as u32, denominator));
+                }
+            }
+        }
+        time_signatures.sort_by_key(|(tick, _, _)| *tick);
+        BarMap::from_time_signatures(midi_file.header.m_time_division, &time_signatures)
+    }
+
+    // time_signatures是(tick, 分子, 分母) 需要按tick排好序
+    // SMPTE时间分辨率下没有四分音符的长度 按120BPM换算
+    pub fn from_time_signatures(
+        time_division: TimeDivision,
+        time_signatures: &[(u64, u32, u32)],
+    ) -> BarMap {
+        let ticks_per_quarter = match time_division {
+            TimeDivision::TicksPerQuarter(ticks) => ticks as u64,
+            TimeDivision::Smpte { .. } => {
+                let (ticks, seconds) = time_division.smpte_ticks_per_second().unwrap();
+                ticks / seconds.max(1) / 2
+            }
+        }
+        .max(1);
+        let beat_ticks =
+            |denominator: u32| (ticks_per_quarter * 4 / denominator.max(1) as u64).max(1);
+        let mut bar_map = BarMap {
+            m_segments: vec![MeterSegment {
+                m_tick: 0,
+                m_bar: 0,
+                m_beats: 4,
+                m_beat_ticks: ticks_per_quarter,
+            }],
+        };
+        for &(tick, numerator, denominator) in time_signatures.iter() {
+            let last = *bar_map.m_segments.last().unwrap();
+            let segment = MeterSegment {
+                m_tick: tick,
+                m_bar: last.m_bar + (tick - last.m_tick).div_ceil(last.bar_ticks()) as u32,
+                m_beats: numerator.max(1),
+                m_beat_ticks: beat_ticks(denominator),
+            };
+            if last.m_tick == tick {
+                *bar_map.m_segments.last_mut().unwrap() = segment;
+            } else {
+                bar_map.m_segments.push(segment);
+            }
+        }
+        bar_map
+    }
+
+    // 第bar小节第beat拍开始的tick 超出这一小节的拍数时继续往后数
+    pub fn bar_to_tick(&self, bar: u32, beat: u32) -> u64 {
+        let bar = bar.max(1) - 1;
+        let index = self
+            .m_segments
+            .partition_point(|segment| segment.m_bar <= bar)
+            - 1;
+        let segment = &self.m_segments[index];
+        segment.m_tick
+            + (bar - segment.m_bar) as u64 * segment.bar_ticks()
+            + (beat.max(1) - 1) as u64 * segment.m_beat_ticks
+    }
+
+    // tick所在的(小节, 拍)
+    pub fn tick_to_bar(&self, tick: u64) -> (u32, u32) {
+        let index = self
+            .m_segments
+            .partition_point(|segment| segment.m_tick <= tick)
+            - 1;
+        let segment = &self.m_segments[index];
+        let offset = tick - segment.m_tick;
+        let bar = segment.m_bar + (offset / segment.bar_ticks()) as u32;
+        let beat = (offset % segment.bar_ticks() / segment.m_beat_ticks) as u32;
+        (bar + 1, beat + 1)
+    }
+}
+
+impl MeterSegment {
+    fn bar_ticks(&self) -> u64 {
+        self.m_beats as u64 * self.m_beat_ticks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 没有拍号时按4/4拍
+    #[test]
+    fn default_meter() {
+        let bar_map = BarMap::from_time_signatures(TimeDivision::TicksPerQuarter(480), &[]);
+        assert_eq!(bar_map.bar_to_tick(1, 1), 0);
+        assert_eq!(bar_map.bar_to_tick(2, 1), 1920);
+        assert_eq!(bar_map.bar_to_tick(2, 3), 2880);
+        assert_eq!(bar_map.tick_to_bar(0), (1, 1));
+        assert_eq!(bar_map.tick_to_bar(1919), (1, 4));
+        assert_eq!(bar_map.tick_to_bar(2400), (2, 2));
+        // 0当成1
+        assert_eq!(bar_map.bar_to_tick(0, 0), 0);
+    }
+
+    #[test]
+    fn eighth_note_beats() {
+        let bar_map =
+            BarMap::from_time_signatures(TimeDivision::TicksPerQuarter(480), &[(0, 6, 8)]);
+        assert_eq!(bar_map.bar_to_tick(2, 1), 1440);
+        assert_eq!(bar_map.bar_to_tick(1, 4), 720);
+        assert_eq!(bar_map.tick_to_bar(1440 + 250), (2, 2));
+    }
+
+    // 第2小节第2拍变成3/4拍 从那里开始第3小节
+    #[test]
+    fn meter_change_mid_bar() {
+        let bar_map = BarMap::from_time_signatures(
+            TimeDivision::TicksPerQuarter(480),
+            &[(0, 4, 4), (2400, 3, 4)],
+        );
+        assert_eq!(bar_map.tick_to_bar(2000), (2, 1));
+        assert_eq!(bar_map.tick_to_bar(2399), (2, 1));
+        assert_eq!(bar_map.tick_to_bar(2400), (3, 1));
+        assert_eq!(bar_map.tick_to_bar(3839), (3, 3));
+        assert_eq!(bar_map.bar_to_tick(3, 1), 2400);
+        assert_eq!(bar_map.bar_to_tick(4, 1), 3840);
+        for (bar, beat) in [(1, 1), (2, 1), (3, 3), (5, 2)] {
+            assert_eq!(
+                bar_map.tick_to_bar(bar_map.bar_to_tick(bar, beat)),
+                (bar, beat)
+            );
+        }
+    }
+
+    // 在小节线上变化时不多出一小节
+    #[test]
+    fn meter_change_on_bar_line() {
+        let bar_map = BarMap::from_time_signatures(
+            TimeDivision::TicksPerQuarter(480),
+            &[(0, 3, 4), (2880, 2, 2)],
+        );
+        assert_eq!(bar_map.bar_to_tick(3, 1), 2880);
+        assert_eq!(bar_map.bar_to_tick(4, 1), 2880 + 1920);
+        assert_eq!(bar_map.tick_to_bar(2880 + 960), (3, 2));
+    }
+
+    // SMPTE 25帧x40 每秒1000 tick 按120BPM每拍500 tick
+    #[test]
+    fn smpte() {
+        let bar_map = BarMap::from_time_signatures(
+            TimeDivision::Smpte {
+                frames_per_second: 25,
+                ticks_per_frame: 40,
+            },
+            &[],
+        );
+        assert_eq!(bar_map.bar_to_tick(2, 1), 2000);
+        assert_eq!(bar_map.tick_to_bar(2500), (2, 2));
+    }
+}
@@ -1,12 +1,13 @@
-use std::error::Error;
 use bitflags::bitflags;
 
+use super::error::MidiParseError;
+
 pub(crate) trait Parser
 where
     Self: Sized,
 {
     fn new(raw_data: &[u8]) -> Self;
-    fn parse(raw_data: &[u8]) -> Result<Self, Box<dyn Error>>;
+    fn parse(raw_data: &[u8]) -> Result<Self, MidiParseError>;
     fn get_raw(&self) -> Vec<u8>;
 }
 
@@ -31,4 +32,94 @@ bitflags! {
         const command = 0b0111_0000;    // 高三位表示命令 也就是消息类型
         const channel = 0b0000_1111;    // 低四位表示通道
     }
-}
\ No newline at end of file
+}
+
+// MIDI通道 内部用0-15表示 显示时按习惯从1开始
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MidiChannel(u8);
+
+impl MidiChannel {
+    pub const DRUM: MidiChannel = MidiChannel(9); // GM规定第10通道是打击乐
+
+    pub fn new(index: u8) -> Option<MidiChannel> {
+        (index < 16).then_some(MidiChannel(index))
+    }
+
+    pub fn index(&self) -> u8 {
+        self.0
+    }
+}
+
+impl std::fmt::Display for MidiChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0 + 1)
+    }
+}
+
+impl MidiStatusByte {
+    pub fn channel_value(&self) -> MidiChannel {
+        MidiChannel(self.intersection(MidiStatusByte::channel).bits())
+    }
+}
+
+// 写文件时running status的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RunningStatus {
+    #[default]
+    Preserve, // 和读入时保持一致 用来保证原样写回
+    Compress, // 状态字节和前一个相同时都省略
+    Expand,   // 每个通道消息都写状态字节
+}
+
+// 从raw_data开头读取一个变长int 返回(原始字节, 占用的字节数)
+pub fn read_midi_int(raw_data: &[u8]) -> Result<(Vec<MidiInt>, usize), MidiParseError> {
+    let mut midi_int: Vec<MidiInt> = Vec::new();
+    for byte in raw_data.iter() {
+        let value = MidiInt::from_bits_retain(*byte);
+        midi_int.push(value);
+        if !value.contains(MidiInt::flag) {
+            let size = midi_int.len();
+            return Ok((midi_int, size));
+        }
+        // 变长int最多4个字节
+        if midi_int.len() >= 4 {
+            return Err(MidiParseError::InvalidVlq {
+                offset: 0,
+                track: None,
+            });
+        }
+    }
+    Err(MidiParseError::eof(midi_int.len()))
+}
+
+// 4个字节的变长int能表示的最大值
+pub const MIDI_INT_MAX: u32 = 0x0FFF_FFFF;
+
+// 把数值编码成变长int 高位在前 除了最后一个字节都要设置最高位
+pub fn midi_int_from_value(value: u32) -> Vec<MidiInt> {
+    let mut midi_int = vec![MidiInt::from_bits_retain((value & 0x7F) as u8)];
+    let mut value = value >> 7;
+    while value > 0 {
+        midi_int.push(MidiInt::from_bits_retain((value & 0x7F) as u8) | MidiInt::flag);
+        value >>= 7;
+    }
+    midi_int.reverse();
+    midi_int
+}
+
+fn write_midi_int(raw_data: &mut Vec<u8>, value: u32) {
+    raw_data.extend(midi_int_from_value(value).iter().map(|byte| byte.bits()));
+}
+
+// 写入 <length: 变长int> <data>
+pub fn write_with_length(raw_data: &mut Vec<u8>, data: &[u8]) {
+    write_midi_int(raw_data, data.len() as u32);
+    raw_data.extend_from_slice(data);
+}
+
+// 把变长int的各个字节合并成数值
+pub fn midi_int_value(midi_int: &[MidiInt]) -> u32 {
+    midi_int.iter().fold(0u32, |sum, byte| {
+        (sum << 7) | (MidiInt::data & *byte).bits() as u32
+    })
+}
@@ -0,0 +1,100 @@
+use super::{base::Parser, error::MidiParseError};
+
+const CHUNK_MAGIC_SIZE: usize = 4;
+const CHUNK_SECLEN_SIZE: usize = 4;
+pub const CHUNK_HEADER_SIZE: usize = CHUNK_MAGIC_SIZE + CHUNK_SECLEN_SIZE;
+
+// 所有chunk共用的头 4个字节的标识 + 4个字节的长度(大端)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub m_magic: [u8; 4],
+    pub m_size: u32,
+}
+
+impl ChunkHeader {
+    pub fn parse(raw_data: &[u8]) -> Result<ChunkHeader, MidiParseError> {
+        if raw_data.len() < CHUNK_HEADER_SIZE {
+            return Err(MidiParseError::eof(raw_data.len()));
+        }
+        Ok(ChunkHeader {
+            m_magic: raw_data[..CHUNK_MAGIC_SIZE].try_into().unwrap(),
+            m_size: u32::from_be_bytes(
+                raw_data[CHUNK_MAGIC_SIZE..CHUNK_HEADER_SIZE]
+                    .try_into()
+                    .unwrap(),
+            ),
+        })
+    }
+
+    // 标识必须是4个可打印的ASCII字符 否则多半是垃圾数据
+    pub fn is_valid(&self) -> bool {
+        self.m_magic
+            .iter()
+            .all(|byte| byte.is_ascii_graphic() || *byte == b' ')
+    }
+
+    pub fn expect(&self, magic: &[u8; 4]) -> Result<(), MidiParseError> {
+        if self.m_magic != *magic {
+            return Err(MidiParseError::BadChunkMagic {
+                offset: 0,
+                track: None,
+                expected: *magic,
+                found: self.m_magic,
+            });
+        }
+        Ok(())
+    }
+
+    // chunk的结束位置 相对于chunk头的开头
+    pub fn end(&self) -> usize {
+        CHUNK_HEADER_SIZE + self.m_size as usize
+    }
+}
+
+// 数据结束或者开头是一个合法的chunk头
+pub fn is_chunk_start(raw_data: &[u8]) -> bool {
+    raw_data.is_empty()
+        || ChunkHeader::parse(raw_data)
+            .map(|chunk_header| chunk_header.is_valid())
+            .unwrap_or(false)
+}
+
+// 不认识的chunk(厂商自定义的数据等) 原样保存
+// 写回时放在第m_track_index个音轨之前
+#[derive(Debug, Clone)]
+pub struct UnknownChunk {
+    pub m_magic: [u8; 4],
+    pub m_data: Vec<u8>,
+    pub m_track_index: usize,
+}
+
+impl Parser for UnknownChunk {
+    fn new(_: &[u8]) -> UnknownChunk {
+        UnknownChunk {
+            m_magic: [0; 4],
+            m_data: Vec::new(),
+            m_track_index: 0,
+        }
+    }
+
+    fn parse(raw_data: &[u8]) -> Result<UnknownChunk, MidiParseError> {
+        let chunk_header = ChunkHeader::parse(raw_data)?;
+        let m_data = raw_data
+            .get(CHUNK_HEADER_SIZE..chunk_header.end())
+            .ok_or(MidiParseError::eof(raw_data.len()))?
+            .to_vec();
+        Ok(UnknownChunk {
+            m_magic: chunk_header.m_magic,
+            m_data,
+            m_track_index: 0,
+        })
+    }
+
+    fn get_raw(&self) -> Vec<u8> {
+        let mut raw_data: Vec<u8> = Vec::with_capacity(CHUNK_HEADER_SIZE + self.m_data.len());
+        raw_data.extend_from_slice(&self.m_magic);
+        raw_data.extend_from_slice(&(self.m_data.len() as u32).to_be_bytes());
+        raw_data.extend_from_slice(&self.m_data);
+        raw_data
+    }
+}
@@ -0,0 +1,304 @@
+use std::{error::Error, fmt, io};
+
+// 解析MIDI数据时的错误 offset是出错位置在整个文件里的字节偏移
+// track是出错的音轨序号 头部或者实时消息里的错误没有音轨
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidiParseError {
+    // 数据提前结束
+    UnexpectedEof {
+        offset: usize,
+        track: Option<usize>,
+    },
+    // chunk的标识不是预期的 "MThd" 或者 "MTrk"
+    BadChunkMagic {
+        offset: usize,
+        track: Option<usize>,
+        expected: [u8; 4],
+        found: [u8; 4],
+    },
+    // chunk声明的长度不合理 比如MThd的长度小于6
+    InvalidChunkSize {
+        offset: usize,
+        track: Option<usize>,
+        size: u32,
+    },
+    // 头部声明的音轨数和实际找到的MTrk数量不一致
+    TrackCountMismatch {
+        offset: usize,
+        track: Option<usize>,
+        declared: u16,
+        found: usize,
+    },
+    // 缺少必需的chunk 比如RMID里没有"data"
+    MissingChunk {
+        offset: usize,
+        track: Option<usize>,
+        magic: [u8; 4],
+    },
+    // 变长int超过4个字节
+    InvalidVlq {
+        offset: usize,
+        track: Option<usize>,
+    },
+    // 音轨开头就省略了状态字节
+    RunningStatusWithoutStatus {
+        offset: usize,
+        track: Option<usize>,
+    },
+    // 数据字节的最高位必须是0
+    DataByteHighBit {
+        offset: usize,
+        track: Option<usize>,
+        byte: u8,
+    },
+    // 流式读取时底层的读取出错
+    Io {
+        offset: usize,
+        track: Option<usize>,
+        kind: io::ErrorKind,
+    },
+    // 文件里不允许出现的系统消息 (F1-F6 F8-FE)
+    UnsupportedStatus {
+        offset: usize,
+        track: Option<usize>,
+        status: u8,
+    },
+}
+
+impl MidiParseError {
+    pub fn offset(&self) -> usize {
+        match self {
+            MidiParseError::UnexpectedEof { offset, .. }
+            | MidiParseError::BadChunkMagic { offset, .. }
+            | MidiParseError::InvalidChunkSize { offset, .. }
+            | MidiParseError::TrackCountMismatch { offset, .. }
+            | MidiParseError::MissingChunk { offset, .. }
+            | MidiParseError::InvalidVlq { offset, .. }
+            | MidiParseError::RunningStatusWithoutStatus { offset, .. }
+            | MidiParseError::DataByteHighBit { offset, .. }
+            | MidiParseError::Io { offset, .. }
+            | MidiParseError::UnsupportedStatus { offset, .. } => *offset,
+        }
+    }
+
+    pub fn track(&self) -> Option<usize> {
+        match self {
+            MidiParseError::UnexpectedEof { track, .. }
+            | MidiParseError::BadChunkMagic { track, .. }
+            | MidiParseError::InvalidChunkSize { track, .. }
+            | MidiParseError::TrackCountMismatch { track, .. }
+            | MidiParseError::MissingChunk { track, .. }
+            | MidiParseError::InvalidVlq { track, .. }
+            | MidiParseError::RunningStatusWithoutStatus { track, .. }
+            | MidiParseError::DataByteHighBit { track, .. }
+            | MidiParseError::Io { track, .. }
+            | MidiParseError::UnsupportedStatus { track, .. } => *track,
+        }
+    }
+
+    // 解析子切片时偏移是相对的 返回上层时加上子切片的起始位置
+    pub fn at(mut self, base: usize) -> MidiParseError {
+        match &mut self {
+            MidiParseError::UnexpectedEof { offset, .. }
+            | MidiParseError::BadChunkMagic { offset, .. }
+            | MidiParseError::InvalidChunkSize { offset, .. }
+            | MidiParseError::TrackCountMismatch { offset, .. }
+            | MidiParseError::MissingChunk { offset, .. }
+            | MidiParseError::InvalidVlq { offset, .. }
+            | MidiParseError::RunningStatusWithoutStatus { offset, .. }
+            | MidiParseError::DataByteHighBit { offset, .. }
+            | MidiParseError::Io { offset, .. }
+            | MidiParseError::UnsupportedStatus { offset, .. } => *offset += base,
+        }
+        self
+    }
+
+    pub fn in_track(mut self, index: usize) -> MidiParseError {
+        match &mut self {
+            MidiParseError::UnexpectedEof { track, .. }
+            | MidiParseError::BadChunkMagic { track, .. }
+            | MidiParseError::InvalidChunkSize { track, .. }
+            | MidiParseError::TrackCountMismatch { track, .. }
+            | MidiParseError::MissingChunk { track, .. }
+            | MidiParseError::InvalidVlq { track, .. }
+            | MidiParseError::RunningStatusWithoutStatus { track, .. }
+            | MidiParseError::DataByteHighBit { track, .. }
+            | MidiParseError::Io { track, .. }
+            | MidiParseError::UnsupportedStatus { track, .. } => *track = Some(index),
+        }
+        self
+    }
+
+    pub(crate) fn eof(offset: usize) -> MidiParseError {
+        MidiParseError::UnexpectedEof {
+            offset,
+            track: None,
+        }
+    }
+}
+
+impl fmt::Display for MidiParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MidiParseError::UnexpectedEof { .. } => write!(f, "unexpected end of data")?,
+            MidiParseError::BadChunkMagic {
+                expected, found, ..
+            } => write!(
+                f,
+                "bad chunk magic: expected {:?}, found {:?}",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(found)
+            )?,
+            MidiParseError::InvalidChunkSize { size, .. } => {
+                write!(f, "invalid chunk size {size}")?
+            }
+            MidiParseError::TrackCountMismatch {
+                declared, found, ..
+            } => write!(
+                f,
+                "header declares {declared} tracks but {found} were found"
+            )?,
+            MidiParseError::MissingChunk { magic, .. } => {
+                write!(f, "missing {:?} chunk", String::from_utf8_lossy(magic))?
+            }
+            MidiParseError::InvalidVlq { .. } => write!(f, "variable-length quantity too long")?,
+            MidiParseError::RunningStatusWithoutStatus { .. } => {
+                write!(f, "running status without a previous status byte")?
+            }
+            MidiParseError::DataByteHighBit { byte, .. } => {
+                write!(f, "data byte {byte:#04x} has the high bit set")?
+            }
+            MidiParseError::Io { kind, .. } => write!(f, "I/O error: {kind}")?,
+            MidiParseError::UnsupportedStatus { status, .. } => {
+                write!(f, "unsupported status byte {status:#04x}")?
+            }
+        }
+        write!(f, " at byte {}", self.offset())?;
+        if let Some(track) = self.track() {
+            write!(f, " in track {track}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for MidiParseError {}
+
+// 宽松模式下遇到的问题 解析会继续 offset和track的含义同MidiParseError
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseWarning {
+    // 头部声明的音轨数和实际找到的不一致 以实际的为准
+    TrackCountMismatch {
+        offset: usize,
+        declared: u16,
+        found: usize,
+    },
+    // 音轨声明的长度和End of Track的位置对不上 以End of Track为准
+    TrackLengthMismatch {
+        offset: usize,
+        track: Option<usize>,
+        declared: u32,
+        actual: u32,
+    },
+    // 音轨没有End of Track
+    MissingEndOfTrack {
+        offset: usize,
+        track: Option<usize>,
+    },
+    // 音轨中途解析出错 保留之前的事件 丢弃之后的数据
+    TrackTruncated {
+        error: MidiParseError,
+    },
+    // 容器里带的音色库格式不支持 kind是chunk的类型 比如"DLS "
+    UnsupportedSoundBank {
+        offset: usize,
+        kind: [u8; 4],
+    },
+    // 最后一个chunk之后的多余数据
+    TrailingData {
+        offset: usize,
+        size: usize,
+    },
+}
+
+impl ParseWarning {
+    pub fn offset(&self) -> usize {
+        match self {
+            ParseWarning::TrackCountMismatch { offset, .. }
+            | ParseWarning::TrackLengthMismatch { offset, .. }
+            | ParseWarning::MissingEndOfTrack { offset, .. }
+            | ParseWarning::UnsupportedSoundBank { offset, .. }
+            | ParseWarning::TrailingData { offset, .. } => *offset,
+            ParseWarning::TrackTruncated { error } => error.offset(),
+        }
+    }
+
+    pub fn track(&self) -> Option<usize> {
+        match self {
+            ParseWarning::TrackLengthMismatch { track, .. }
+            | ParseWarning::MissingEndOfTrack { track, .. } => *track,
+            ParseWarning::TrackTruncated { error } => error.track(),
+            ParseWarning::TrackCountMismatch { .. }
+            | ParseWarning::UnsupportedSoundBank { .. }
+            | ParseWarning::TrailingData { .. } => None,
+        }
+    }
+
+    pub fn at(mut self, base: usize) -> ParseWarning {
+        match &mut self {
+            ParseWarning::TrackCountMismatch { offset, .. }
+            | ParseWarning::TrackLengthMismatch { offset, .. }
+            | ParseWarning::MissingEndOfTrack { offset, .. }
+            | ParseWarning::UnsupportedSoundBank { offset, .. }
+            | ParseWarning::TrailingData { offset, .. } => *offset += base,
+            ParseWarning::TrackTruncated { error } => *error = error.clone().at(base),
+        }
+        self
+    }
+
+    pub fn in_track(mut self, index: usize) -> ParseWarning {
+        match &mut self {
+            ParseWarning::TrackLengthMismatch { track, .. }
+            | ParseWarning::MissingEndOfTrack { track, .. } => *track = Some(index),
+            ParseWarning::TrackTruncated { error } => *error = error.clone().in_track(index),
+            ParseWarning::TrackCountMismatch { .. }
+            | ParseWarning::UnsupportedSoundBank { .. }
+            | ParseWarning::TrailingData { .. } => {}
+        }
+        self
+    }
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseWarning::TrackCountMismatch {
+                declared, found, ..
+            } => write!(
+                f,
+                "header declares {declared} tracks but {found} were found"
+            )?,
+            ParseWarning::UnsupportedSoundBank { kind, .. } => write!(
+                f,
+                "embedded {:?} sound bank is not supported, using the configured soundfont",
+                String::from_utf8_lossy(kind).trim_end()
+            )?,
+            ParseWarning::TrackLengthMismatch {
+                declared, actual, ..
+            } => write!(
+                f,
+                "declared track length {declared} bytes, End of Track found after {actual}"
+            )?,
+            ParseWarning::MissingEndOfTrack { .. } => write!(f, "missing End of Track")?,
+            // 错误本身已经带了位置
+            ParseWarning::TrackTruncated { error } => return write!(f, "track truncated: {error}"),
+            ParseWarning::TrailingData { size, .. } => {
+                write!(f, "ignored {size} bytes of trailing data")?
+            }
+        }
+        write!(f, " at byte {}", self.offset())?;
+        if let Some(track) = self.track() {
+            write!(f, " in track {track}")?;
+        }
+        Ok(())
+    }
+}
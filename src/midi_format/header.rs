@@ -1,13 +1,66 @@
-use super::base::Parser;
-use std::{error::Error, io::Cursor, mem::take};
+use super::{base::Parser, chunk::ChunkHeader, error::MidiParseError};
+use std::{io::Cursor, mem::take};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Header {
-    pub m_magic: [u8; 4],     // MIDI头部标识 值为"MThd"或者 "MTrk"
-    pub m_header_size: u32,   // 头部大小
-    pub m_format: u16,        // MIDI格式
-    pub m_num_tracks: u16,    // 音轨数
-    pub m_time_division: u16, // 时间分辨率
+    pub m_magic: [u8; 4],              // MIDI头部标识 值为"MThd"或者 "MTrk"
+    pub m_header_size: u32,            // 头部大小
+    pub m_format: u16,                 // MIDI格式
+    pub m_num_tracks: u16,             // 音轨数
+    pub m_time_division: TimeDivision, // 时间分辨率
+    pub m_extra: Vec<u8>,              // 头部比6个字节长的部分 写回时原样保留
+}
+
+// 时间分辨率 最高位为0时表示每个四分音符的tick数
+// 最高位为1时 高字节是负的SMPTE帧率(-24/-25/-29/-30) 低字节是每帧的tick数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeDivision {
+    TicksPerQuarter(u16),
+    Smpte {
+        frames_per_second: u8,
+        ticks_per_frame: u8,
+    },
+}
+
+impl TimeDivision {
+    pub fn from_bits(bits: u16) -> TimeDivision {
+        if bits & 0x8000 == 0 {
+            return TimeDivision::TicksPerQuarter(bits);
+        }
+        let [frames, ticks_per_frame] = bits.to_be_bytes();
+        TimeDivision::Smpte {
+            frames_per_second: (frames as i8).unsigned_abs(),
+            ticks_per_frame,
+        }
+    }
+
+    pub fn bits(&self) -> u16 {
+        match *self {
+            TimeDivision::TicksPerQuarter(ticks) => ticks,
+            TimeDivision::Smpte {
+                frames_per_second,
+                ticks_per_frame,
+            } => u16::from_be_bytes([
+                (frames_per_second as i8).wrapping_neg() as u8,
+                ticks_per_frame,
+            ]),
+        }
+    }
+
+    // SMPTE模式下每秒的tick数 用分数表示 (分子, 分母) 29表示29.97帧(30000/1001)
+    pub fn smpte_ticks_per_second(&self) -> Option<(u64, u64)> {
+        match *self {
+            TimeDivision::TicksPerQuarter(_) => None,
+            TimeDivision::Smpte {
+                frames_per_second: 29,
+                ticks_per_frame,
+            } => Some((30000 * ticks_per_frame as u64, 1001)),
+            TimeDivision::Smpte {
+                frames_per_second,
+                ticks_per_frame,
+            } => Some((frames_per_second as u64 * ticks_per_frame as u64, 1)),
+        }
+    }
 }
 
 const HEADER_MAGIC_SIZE: usize = 4;
@@ -16,7 +69,7 @@ const HEADER_FORMAT_SIZE: usize = 2;
 const HEADER_NTRACKS_SIZE: usize = 2;
 const HEADER_TICKDIV_SIZE: usize = 2;
 
-const HEADER_SIZE: usize = 14;
+pub const HEADER_SIZE: usize = 14;
 impl Parser for Header {
     fn new(_: &[u8]) -> Self {
         Header {
@@ -24,23 +77,38 @@ impl Parser for Header {
             m_header_size: 0,
             m_format: 0,
             m_num_tracks: 0,
-            m_time_division: 0,
+            m_time_division: TimeDivision::TicksPerQuarter(0),
+            m_extra: Vec::new(),
         }
     }
 
     fn get_raw(&self) -> Vec<u8> {
-        todo!()
+        let mut raw_data: Vec<u8> = Vec::with_capacity(HEADER_SIZE + self.m_extra.len());
+        raw_data.extend_from_slice(b"MThd");
+        raw_data.extend_from_slice(&self.m_header_size.to_be_bytes());
+        raw_data.extend_from_slice(&self.m_format.to_be_bytes());
+        raw_data.extend_from_slice(&self.m_num_tracks.to_be_bytes());
+        raw_data.extend_from_slice(&self.m_time_division.bits().to_be_bytes());
+        raw_data.extend_from_slice(&self.m_extra);
+        raw_data
     }
-    fn parse(raw_data: &[u8]) -> Result<Header, Box<dyn Error>> {
-        let mut cursor = 0;
-        let m_magic: [u8; 4] = raw_data[..HEADER_MAGIC_SIZE].try_into().unwrap();
-        cursor += HEADER_MAGIC_SIZE;
-        let m_header_size = u32::from_be_bytes(
-            raw_data[cursor..cursor + HEADER_SCELEN_SIZE]
-                .try_into()
-                .unwrap(),
-        );
-        cursor += HEADER_SCELEN_SIZE;
+    fn parse(raw_data: &[u8]) -> Result<Header, MidiParseError> {
+        if raw_data.len() < HEADER_SIZE {
+            return Err(MidiParseError::eof(raw_data.len()));
+        }
+        let chunk_header = ChunkHeader::parse(raw_data)?;
+        chunk_header.expect(b"MThd")?;
+        let m_magic = chunk_header.m_magic;
+        let m_header_size = chunk_header.m_size;
+        // 头部至少有format ntracks division三个字段 更长的部分留给以后的版本 跳过
+        if (m_header_size as usize) < HEADER_SIZE - HEADER_MAGIC_SIZE - HEADER_SCELEN_SIZE {
+            return Err(MidiParseError::InvalidChunkSize {
+                offset: HEADER_MAGIC_SIZE,
+                track: None,
+                size: m_header_size,
+            });
+        }
+        let mut cursor = HEADER_MAGIC_SIZE + HEADER_SCELEN_SIZE;
         let m_format = u16::from_be_bytes(
             raw_data[cursor..cursor + HEADER_FORMAT_SIZE]
                 .try_into()
@@ -53,17 +121,24 @@ impl Parser for Header {
                 .unwrap(),
         );
         cursor += HEADER_NTRACKS_SIZE;
-        let m_time_division = u16::from_be_bytes(
+        let m_time_division = TimeDivision::from_bits(u16::from_be_bytes(
             raw_data[cursor..cursor + HEADER_TICKDIV_SIZE]
                 .try_into()
                 .unwrap(),
-        );
+        ));
+        // 多出来的字节不在raw_data里时留空 由调用者读入
+        let extra_end = HEADER_MAGIC_SIZE + HEADER_SCELEN_SIZE + m_header_size as usize;
+        let m_extra = raw_data
+            .get(HEADER_SIZE..extra_end)
+            .map(<[u8]>::to_vec)
+            .unwrap_or_default();
         Ok(Header {
             m_magic,
             m_header_size,
             m_format,
             m_num_tracks,
             m_time_division,
+            m_extra,
         })
     }
 }
@@ -0,0 +1,227 @@
+use std::mem::take;
+
+use super::{
+    midi_message::{Event, MetaEvent},
+    sequencer::merge_tracks,
+    tempo_map::TempoMap,
+    MidiFile,
+};
+
+// .kar文件开头用'@'开头的Text事件记录歌曲信息
+// @K 文件类型 @V 版本 @L 语言 @T 标题/作者等(可以有多行) @I 其他信息(可以有多行)
+#[derive(Debug, Clone, Default)]
+pub struct KaraokeTags {
+    pub m_version: Option<String>,
+    pub m_language: Option<String>,
+    pub m_title: Vec<String>,
+    pub m_info: Vec<String>,
+}
+
+// 一个音节 到m_tick时开始唱
+#[derive(Debug, Clone)]
+pub struct Syllable {
+    pub m_tick: u64,
+    pub m_micros: u64, // 从开头算起的时间
+    pub m_text: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LyricLine {
+    pub m_new_paragraph: bool, // '\'开始的行 前面要空一行
+    pub m_syllables: Vec<Syllable>,
+}
+
+impl LyricLine {
+    pub fn text(&self) -> String {
+        self.m_syllables
+            .iter()
+            .map(|syllable| syllable.m_text.as_str())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Karaoke {
+    pub m_tags: KaraokeTags,
+    pub m_lines: Vec<LyricLine>,
+}
+
+impl Karaoke {
+    // .kar的歌词在Text事件里 '/'开始新的一行 '\'开始新的一段
+    // 没有.kar标记(@K)时只用Lyric事件 普通文件的Text事件是版权/注释之类 不是歌词
+    // Lyric事件的换行也可能写成'\r'或者'\n'
+    pub fn new(midi_file: &MidiFile) -> Karaoke {
+        let tempo_map = TempoMap::new(midi_file);
+        let mut karaoke = Karaoke::default();
+        let mut texts: Vec<(u64, String)> = Vec::new();
+        let mut lyrics: Vec<(u64, String)> = Vec::new();
+        let mut is_kar = false;
+        for sequenced_event in merge_tracks(midi_file) {
+            let Event::Meta { meta } = &sequenced_event.m_message.m_ment_event else {
+                continue;
+            };
+            let Some(text) = meta.text() else {
+                continue;
+            };
+            match meta {
+                MetaEvent::Text { .. } if text.starts_with('@') => {
+                    is_kar |= karaoke.m_tags.push(&text);
+                }
+                MetaEvent::Text { .. } => texts.push((sequenced_event.m_tick, text)),
+                MetaEvent::Lyric { .. } => lyrics.push((sequenced_event.m_tick, text)),
+                _ => {}
+            }
+        }
+        let syllables = if is_kar { texts } else { lyrics };
+        if syllables.is_empty() {
+            return Karaoke::default();
+        }
+
+        let mut line = LyricLine::default();
+        for (tick, text) in syllables {
+            let mut text = text.as_str();
+            if let Some(rest) = text.strip_prefix(['\\', '/']) {
+                // 连续的换行只算一次 但是分段的标记要留给下一行
+                let new_paragraph =
+                    text.starts_with('\\') || (line.m_syllables.is_empty() && line.m_new_paragraph);
+                karaoke.push_line(take(&mut line));
+                line.m_new_paragraph = new_paragraph;
+                text = rest;
+            }
+            let line_break = text.ends_with(['\r', '\n']);
+            let text = text.trim_end_matches(['\r', '\n']);
+            if !text.is_empty() {
+                line.m_syllables.push(Syllable {
+                    m_tick: tick,
+                    m_micros: tempo_map.ticks_to_micros(tick),
+                    m_text: text.to_string(),
+                });
+            }
+            if line_break {
+                karaoke.push_line(take(&mut line));
+            }
+        }
+        karaoke.push_line(line);
+        karaoke
+    }
+
+    // 空行不保存
+    fn push_line(&mut self, line: LyricLine) {
+        if !line.m_syllables.is_empty() {
+            self.m_lines.push(line);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.m_lines.is_empty()
+    }
+
+    // tick时正在唱的行 和这一行已经唱到的音节数 第一个音节之前返回None
+    pub fn position_at(&self, tick: u64) -> Option<(usize, usize)> {
+        let line = self
+            .m_lines
+            .partition_point(|line| line.m_syllables[0].m_tick <= tick)
+            .checked_sub(1)?;
+        let syllables = &self.m_lines[line].m_syllables;
+        Some((
+            line,
+            syllables.partition_point(|syllable| syllable.m_tick <= tick),
+        ))
+    }
+}
+
+impl KaraokeTags {
+    // 返回这个标记是不是.kar的文件标记(@K)
+    fn push(&mut self, text: &str) -> bool {
+        let mut chars = text.trim_start_matches('@').chars();
+        let tag = chars.next();
+        let value = chars.as_str().trim().to_string();
+        match tag {
+            Some('K') => return true,
+            Some('V') => self.m_version = Some(value),
+            Some('L') => self.m_language = Some(value),
+            Some('T') => self.m_title.push(value),
+            Some('I') => self.m_info.push(value),
+            _ => {}
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_format::{
+        header::{Header, TimeDivision},
+        midi_message::MidiMessage,
+        tracks::{MidiTrack, Tracks},
+    };
+
+    fn midi_file(events: Vec<(u64, MetaEvent)>) -> MidiFile {
+        let track = MidiTrack::from_absolute(
+            events
+                .into_iter()
+                .map(|(tick, meta)| (tick, MidiMessage::from_meta(0, meta))),
+        );
+        MidiFile {
+            header: Header {
+                m_magic: *b"MThd",
+                m_header_size: 6,
+                m_format: 0,
+                m_num_tracks: 1,
+                m_time_division: TimeDivision::TicksPerQuarter(480),
+                m_extra: Vec::new(),
+            },
+            tracks: Tracks(vec![track]),
+            unknown_chunks: Vec::new(),
+            embedded_soundfont: None,
+        }
+    }
+
+    fn text(text: &str) -> MetaEvent {
+        MetaEvent::Text {
+            text: text.as_bytes().to_vec(),
+        }
+    }
+
+    // 普通文件里的Text事件不当成歌词
+    #[test]
+    fn plain_text_is_not_lyrics() {
+        let karaoke = Karaoke::new(&midi_file(vec![
+            (0, text("Copyright 2020")),
+            (0, text("generated by sequencer")),
+        ]));
+        assert!(karaoke.is_empty());
+    }
+
+    #[test]
+    fn lyric_events() {
+        let lyric = |text: &str| MetaEvent::Lyric {
+            text: text.as_bytes().to_vec(),
+        };
+        let karaoke = Karaoke::new(&midi_file(vec![
+            (0, text("Copyright 2020")),
+            (0, lyric("Hel")),
+            (240, lyric("lo\r")),
+            (480, lyric("world")),
+        ]));
+        let lines: Vec<String> = karaoke.m_lines.iter().map(LyricLine::text).collect();
+        assert_eq!(lines, ["Hello", "world"]);
+    }
+
+    #[test]
+    fn kar_texts() {
+        let karaoke = Karaoke::new(&midi_file(vec![
+            (0, text("@KMIDI KARAOKE FILE")),
+            (0, text("@TSong")),
+            (0, text("\\Hel")),
+            (240, text("lo")),
+            (480, text("/world")),
+        ]));
+        assert_eq!(karaoke.m_tags.m_title, ["Song"]);
+        let lines: Vec<String> = karaoke.m_lines.iter().map(LyricLine::text).collect();
+        assert_eq!(lines, ["Hello", "world"]);
+        assert!(karaoke.m_lines[0].m_new_paragraph);
+        assert_eq!(karaoke.position_at(300), Some((0, 2)));
+    }
+}
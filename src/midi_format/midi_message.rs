@@ -1,12 +1,11 @@
-use std::error::Error;
-
-use super::*;
+use super::{error::MidiParseError, *};
 
 #[derive(Clone, Debug)]
 pub struct MidiMessage {
     pub m_delta_time: Vec<MidiInt>, // 间隔时间
     pub m_status: MidiStatusByte,   // 状态
     pub m_ment_event: Event,        // 事件
+    pub m_running_status: bool,     // 读入时是否省略了状态字节
 
     m_message_size: usize, // 消息的大小
 }
@@ -15,6 +14,16 @@ impl MidiMessage {
     pub fn get_message_size(&self) -> usize {
         self.m_message_size
     }
+
+    // 解码后的间隔时间(tick)
+    pub fn delta_ticks(&self) -> u32 {
+        midi_int_value(&self.m_delta_time)
+    }
+
+    // 变长int最多表示28位 超出的部分截断 更长的间隔用MidiTrack::from_absolute拆开
+    pub fn set_delta_ticks(&mut self, delta_ticks: u32) {
+        self.m_delta_time = midi_int_from_value(delta_ticks.min(MIDI_INT_MAX));
+    }
 }
 
 impl MidiMessage {
@@ -23,70 +32,250 @@ impl MidiMessage {
             m_delta_time: Vec::new(),
             m_status: MidiStatusByte::empty(),
             m_ment_event: Event::None,
+            m_running_status: false,
             m_message_size: 0,
         }
     }
 
-    fn get_raw(&self) -> Vec<u8> {
-        todo!()
+    // 用事件构造一个消息 delta_ticks是和前一个事件的间隔
+    pub fn from_event(delta_ticks: u32, status: MidiStatusByte, event: Event) -> MidiMessage {
+        let mut midi_message = MidiMessage::new(&[]);
+        midi_message.set_delta_ticks(delta_ticks);
+        midi_message.m_status = status;
+        midi_message.m_ment_event = event;
+        midi_message.m_message_size = midi_message.get_raw().len();
+        midi_message
     }
 
-    pub fn parse(raw_data: &[u8], pre_status: &Option<MidiStatusByte>) -> Result<MidiMessage, Box<dyn Error>> {
-        let mut midi_message = MidiMessage::new(raw_data);
-        let mut cursor = 0;
-        let mut delta_time: Vec<MidiInt> = Vec::new();
-        // deleta_time 的解析方式是 midi变长int 每次读一个字节， 判断最高位是否为1 如果是1则继续读取下一个字节
-        loop {
-            if let Some(midi_delta_time) = MidiInt::from_bits(raw_data[cursor]) {
-                delta_time.push(midi_delta_time.clone());
-                cursor += 1;
-                if !midi_delta_time.contains(MidiInt::flag) {
-                    break;
+    // 构造一个meta事件
+    pub fn from_meta(delta_ticks: u32, meta: MetaEvent) -> MidiMessage {
+        MidiMessage::from_event(
+            delta_ticks,
+            MidiStatusByte::from_bits_retain(META_EVENT_STATUS),
+            Event::Meta { meta },
+        )
+    }
+
+    // 单独编码一个消息 总是写出状态字节
+    pub fn get_raw(&self) -> Vec<u8> {
+        let mut raw_data = Vec::new();
+        self.write_raw(&mut raw_data, &mut None, RunningStatus::Expand);
+        raw_data
+    }
+
+    // 把消息写到raw_data后面 pre_status是前一个写出的状态字节
+    pub fn write_raw(
+        &self,
+        raw_data: &mut Vec<u8>,
+        pre_status: &mut Option<MidiStatusByte>,
+        running_status: RunningStatus,
+    ) {
+        raw_data.extend(self.m_delta_time.iter().map(|byte| byte.bits()));
+        match &self.m_ment_event {
+            Event::Midi { message } => {
+                let same_status =
+                    pre_status.map(|status| status.bits()) == Some(self.m_status.bits());
+                let omit_status = match running_status {
+                    RunningStatus::Preserve => self.m_running_status && same_status,
+                    RunningStatus::Compress => same_status,
+                    RunningStatus::Expand => false,
+                };
+                if !omit_status {
+                    raw_data.push(self.m_status.bits());
                 }
-            } else {
-                return Err("delta time error".into());
+                *pre_status = Some(self.m_status);
+                let (data1, data2) = message.data();
+                raw_data.push(data1);
+                if channel_data_size(self.m_status) == Some(2) {
+                    raw_data.push(data2);
+                }
+            }
+            Event::Meta { meta } => {
+                raw_data.extend_from_slice(&[META_EVENT_STATUS, meta.meta_type()]);
+                write_with_length(raw_data, &meta.data());
             }
+            Event::SysEx { sysex } => match sysex {
+                SysExEvent::Message { data } => {
+                    raw_data.push(SYSTEM_EXCLUSIVE_STATUS);
+                    write_with_length(raw_data, data);
+                }
+                SysExEvent::Escape { data } => {
+                    raw_data.push(SYSTEM_EXCLUSIVE_ESCAPE);
+                    write_with_length(raw_data, data);
+                }
+            },
+            Event::None => (),
         }
+        // 按规范meta和sysex会取消running status 原样写回时保持读入的样子
+        if !self.is_channel_message() && running_status != RunningStatus::Preserve {
+            *pre_status = None;
+        }
+    }
+
+    pub fn parse(
+        raw_data: &[u8],
+        pre_status: &Option<MidiStatusByte>,
+    ) -> Result<MidiMessage, MidiParseError> {
+        let mut midi_message = MidiMessage::new(raw_data);
+        let mut cursor = 0;
+        // deleta_time 的解析方式是 midi变长int 每次读一个字节， 判断最高位是否为1 如果是1则继续读取下一个字节
+        let (delta_time, delta_size) = read_midi_int(raw_data)?;
+        cursor += delta_size;
         midi_message.m_delta_time = delta_time;
+        // 0xFF 是meta事件 不参与running status
+        if raw_data.get(cursor) == Some(&META_EVENT_STATUS) {
+            midi_message.m_status = MidiStatusByte::from_bits_retain(META_EVENT_STATUS);
+            cursor += 1;
+            let meta_type = *raw_data.get(cursor).ok_or(MidiParseError::eof(cursor))?;
+            cursor += 1;
+            let (meta_length, length_size) =
+                read_midi_int(&raw_data[cursor..]).map_err(|err| err.at(cursor))?;
+            cursor += length_size;
+            let meta_length = midi_int_value(&meta_length) as usize;
+            let meta_data = raw_data
+                .get(cursor..cursor + meta_length)
+                .ok_or(MidiParseError::eof(raw_data.len()))?;
+            midi_message.m_ment_event = Event::Meta {
+                meta: MetaEvent::parse(meta_type, meta_data),
+            };
+            cursor += meta_length;
+            midi_message.m_message_size = cursor;
+            return Ok(midi_message);
+        }
+        // 0xF0 和 0xF7 是sysex事件 长度同样是变长int
+        if let Some(&status @ (SYSTEM_EXCLUSIVE_STATUS | SYSTEM_EXCLUSIVE_ESCAPE)) =
+            raw_data.get(cursor)
+        {
+            midi_message.m_status = MidiStatusByte::from_bits_retain(status);
+            cursor += 1;
+            let (sysex_length, length_size) =
+                read_midi_int(&raw_data[cursor..]).map_err(|err| err.at(cursor))?;
+            cursor += length_size;
+            let sysex_length = midi_int_value(&sysex_length) as usize;
+            let data = raw_data
+                .get(cursor..cursor + sysex_length)
+                .ok_or(MidiParseError::eof(raw_data.len()))?
+                .to_vec();
+            midi_message.m_ment_event = Event::SysEx {
+                sysex: match status {
+                    SYSTEM_EXCLUSIVE_STATUS => SysExEvent::Message { data },
+                    _ => SysExEvent::Escape { data },
+                },
+            };
+            cursor += sysex_length;
+            midi_message.m_message_size = cursor;
+            return Ok(midi_message);
+        }
         // 解析状态字节
-        if let Some(status) = MidiStatusByte::from_bits(raw_data[cursor]) {
-            // 如果status 的最高位为1 则表示这是一个状态字节
-            // 如果不为1 则使用pre_status
-            if !status.contains(MidiStatusByte::flag){
-                if let Some(pre_status) = pre_status {
-                    midi_message.m_status = pre_status.clone();
-                } else {
-                    return Err("status error".into());
-                }
-            }else {
-                midi_message.m_status = status;
-                cursor += 1;
+        let status = raw_data
+            .get(cursor)
+            .map(|status| MidiStatusByte::from_bits_retain(*status))
+            .ok_or(MidiParseError::eof(cursor))?;
+        // 如果status 的最高位为1 则表示这是一个状态字节
+        // 如果不为1 则使用pre_status
+        if !status.contains(MidiStatusByte::flag) {
+            if let Some(pre_status) = pre_status {
+                midi_message.m_status = *pre_status;
+                midi_message.m_running_status = true;
+            } else {
+                return Err(MidiParseError::RunningStatusWithoutStatus {
+                    offset: cursor,
+                    track: None,
+                });
             }
         } else {
-            return Err("status error".into());
+            midi_message.m_status = status;
+            midi_message.check_status(cursor)?;
+            cursor += 1;
         }
-        let ret: MidiStatusByte = midi_message.m_status.intersection(MidiStatusByte::command);
+        cursor += midi_message
+            .parse_channel_event(&raw_data[cursor..])
+            .map_err(|err| err.at(cursor))?;
+        midi_message.m_message_size = cursor;
+        Ok(midi_message)
+    }
+
+    // 解析实时输入的消息 没有delta time 也不会省略状态字节
+    pub fn from_live(raw_data: &[u8]) -> Result<MidiMessage, MidiParseError> {
+        let mut midi_message = MidiMessage::new(raw_data);
+        let status = *raw_data.first().ok_or(MidiParseError::eof(0))?;
+        if status == SYSTEM_EXCLUSIVE_STATUS {
+            // 实时输入的sysex是完整的 F0 ... F7 不带长度
+            midi_message.m_status = MidiStatusByte::from_bits_retain(status);
+            midi_message.m_ment_event = Event::SysEx {
+                sysex: SysExEvent::Message {
+                    data: raw_data[1..].to_vec(),
+                },
+            };
+            midi_message.m_message_size = raw_data.len();
+            return Ok(midi_message);
+        }
+        midi_message.m_status = MidiStatusByte::from_bits_retain(status);
+        if !midi_message.m_status.contains(MidiStatusByte::flag) {
+            return Err(MidiParseError::RunningStatusWithoutStatus {
+                offset: 0,
+                track: None,
+            });
+        }
+        midi_message.check_status(0)?;
+        midi_message.m_message_size = 1 + midi_message
+            .parse_channel_event(&raw_data[1..])
+            .map_err(|err| err.at(1))?;
+        Ok(midi_message)
+    }
+
+    // 文件和实时输入里只处理通道消息 offset是状态字节的位置
+    fn check_status(&self, offset: usize) -> Result<(), MidiParseError> {
+        match channel_data_size(self.m_status) {
+            Some(_) => Ok(()),
+            None => Err(MidiParseError::UnsupportedStatus {
+                offset,
+                track: None,
+                status: self.m_status.bits(),
+            }),
+        }
+    }
+
+    // 根据m_status解析通道消息的数据字节 返回读取的字节数
+    // 状态字节已经由check_status检查过 错误的偏移相对于raw_data的开头
+    fn parse_channel_event(&mut self, raw_data: &[u8]) -> Result<usize, MidiParseError> {
+        let data_size = channel_data_size(self.m_status).unwrap_or_default();
+        if raw_data.len() < data_size {
+            return Err(MidiParseError::eof(raw_data.len()));
+        }
+        if let Some(index) = raw_data[..data_size]
+            .iter()
+            .position(|byte| byte & 0x80 != 0)
+        {
+            return Err(MidiParseError::DataByteHighBit {
+                offset: index,
+                track: None,
+                byte: raw_data[index],
+            });
+        }
+        let mut cursor = 0;
+        let ret: MidiStatusByte = self.m_status.intersection(MidiStatusByte::command);
         match ret.bits() {
             NOTE_OFF_VALUE => {
-                midi_message.m_ment_event = Event::Midi {
+                self.m_ment_event = Event::Midi {
                     message: MessageEvent::NoteOff {
-                        key: MidiDataByte::from_bits(raw_data[cursor]).unwrap(),
-                        velocity: MidiDataByte::from_bits(raw_data[cursor + 1]).unwrap(),
+                        key: MidiDataByte::from_bits_retain(raw_data[cursor]),
+                        velocity: MidiDataByte::from_bits_retain(raw_data[cursor + 1]),
                     },
                 };
                 cursor += 2;
             }
             NOTE_ON_VALUE => {
-                midi_message.m_ment_event = Event::Midi {
+                self.m_ment_event = Event::Midi {
                     message: MessageEvent::NoteOn {
-                        key: MidiDataByte::from_bits(raw_data[cursor]).unwrap(),
-                        velocity: MidiDataByte::from_bits(raw_data[cursor + 1]).unwrap(),
+                        key: MidiDataByte::from_bits_retain(raw_data[cursor]),
+                        velocity: MidiDataByte::from_bits_retain(raw_data[cursor + 1]),
                     },
                 };
                 cursor += 2;
             }
             AFTERTOUCH_VALUE => {
-                midi_message.m_ment_event = Event::Midi {
+                self.m_ment_event = Event::Midi {
                     message: MessageEvent::Aftertouch {
                         key: raw_data[cursor],
                         value: raw_data[cursor + 1],
@@ -95,7 +284,7 @@ impl MidiMessage {
                 cursor += 2;
             }
             CONTROLLER_VALUE => {
-                midi_message.m_ment_event = Event::Midi {
+                self.m_ment_event = Event::Midi {
                     message: MessageEvent::Controller {
                         controller: raw_data[cursor],
                         value: raw_data[cursor + 1],
@@ -104,7 +293,7 @@ impl MidiMessage {
                 cursor += 2;
             }
             PROGRAM_CHANGE_VALUE => {
-                midi_message.m_ment_event = Event::Midi {
+                self.m_ment_event = Event::Midi {
                     message: MessageEvent::ProgramChange {
                         program: raw_data[cursor],
                     },
@@ -112,7 +301,7 @@ impl MidiMessage {
                 cursor += 1;
             }
             CHANNEL_PRESSURE_VALUE => {
-                midi_message.m_ment_event = Event::Midi {
+                self.m_ment_event = Event::Midi {
                     message: MessageEvent::ChannelAftertouch {
                         value: raw_data[cursor],
                     },
@@ -120,34 +309,37 @@ impl MidiMessage {
                 cursor += 1;
             }
             PITCH_WHEEL_VALUE => {
-                // 弯音 特殊处理
-                let value =  u16::from_be_bytes(raw_data[cursor..cursor + 2].try_into()?) & 0x7E;
-                midi_message.m_ment_event = Event::Midi {
-                    message: MessageEvent::PitchWheel {
-                        value: value,
-                    },
+                // 弯音 特殊处理 两个数据字节各取低7位 低位在前 合成14位的值
+                let value =
+                    (raw_data[cursor] & 0x7F) as u16 | ((raw_data[cursor + 1] & 0x7F) as u16) << 7;
+                self.m_ment_event = Event::Midi {
+                    message: MessageEvent::PitchWheel { value },
                 };
                 cursor += 2;
             }
-            SYSTEM_EXCLUSIVE_VALUE => {
-                // 系统消息
-                let system_type = raw_data[cursor];
-                let system_length = raw_data[cursor + 1];
-                let system_data =
-                    raw_data[cursor + 2..cursor + 2 + system_length as usize].to_vec();
-                midi_message.m_ment_event = Event::Midi {
-                    message: MessageEvent::SystemMessage {
-                        system_type,
-                        system_length,
-                        system_data,
-                    },
-                };
-                cursor += 2 + system_length as usize;
-            }
-            _ => todo!("这是什么？"),
+            _ => unreachable!("check_status 已经排除了系统消息"),
+        }
+        Ok(cursor)
+    }
+
+    // meta和sysex事件不会改变running status
+    pub fn is_channel_message(&self) -> bool {
+        matches!(self.m_ment_event, Event::Midi { .. })
+            && self.m_status.bits() < SYSTEM_EXCLUSIVE_STATUS
+    }
+
+    // 通道消息所在的通道 meta和sysex事件没有通道
+    pub fn channel(&self) -> Option<MidiChannel> {
+        self.is_channel_message()
+            .then(|| self.m_status.channel_value())
+    }
+
+    // 把通道消息移到另一个通道 meta和sysex事件不变
+    pub fn set_channel(&mut self, channel: MidiChannel) {
+        if self.is_channel_message() {
+            self.m_status = self.m_status.difference(MidiStatusByte::channel)
+                | MidiStatusByte::from_bits_retain(channel.index());
         }
-        midi_message.m_message_size = cursor;
-        Ok(midi_message)
     }
 }
 
@@ -155,9 +347,209 @@ impl MidiMessage {
 #[derive(Clone, Debug)]
 pub enum Event {
     Midi { message: MessageEvent },
+    Meta { meta: MetaEvent },
+    SysEx { sysex: SysExEvent },
     None,
 }
 
+// 通道消息的数据字节数 系统消息返回None
+pub(crate) fn channel_data_size(status: MidiStatusByte) -> Option<usize> {
+    match status.intersection(MidiStatusByte::command).bits() {
+        PROGRAM_CHANGE_VALUE | CHANNEL_PRESSURE_VALUE => Some(1),
+        SYSTEM_EXCLUSIVE_VALUE => None,
+        _ => Some(2),
+    }
+}
+
+// sysex事件
+// F0 <length> <data> 是一条sysex的开始 data以F7结尾时表示消息完整
+// F7 <length> <data> 是分包sysex的后续部分 也可以用来发送任意数据
+#[derive(Clone, Debug)]
+pub enum SysExEvent {
+    Message { data: Vec<u8> },
+    Escape { data: Vec<u8> },
+}
+
+// 把分包的sysex拼成完整的消息 (包含开头的F0和结尾的F7)
+#[derive(Debug, Default)]
+pub struct SysExAssembler {
+    m_buffer: Option<Vec<u8>>,
+}
+
+impl SysExAssembler {
+    pub fn push(&mut self, sysex: &SysExEvent) -> Option<Vec<u8>> {
+        match sysex {
+            SysExEvent::Message { data } => {
+                let mut buffer = vec![SYSTEM_EXCLUSIVE_STATUS];
+                buffer.extend_from_slice(data);
+                self.m_buffer = Some(buffer);
+            }
+            SysExEvent::Escape { data } => match self.m_buffer.as_mut() {
+                Some(buffer) => buffer.extend_from_slice(data),
+                // 不在分包中的F7事件是转义数据 原样返回
+                None => return Some(data.clone()),
+            },
+        }
+        if self.m_buffer.as_ref()?.last() == Some(&SYSTEM_EXCLUSIVE_ESCAPE) {
+            return self.m_buffer.take();
+        }
+        None
+    }
+}
+
+// meta事件 状态字节为0xFF 格式为 FF <type> <length: 变长int> <data>
+#[derive(Clone, Debug)]
+pub enum MetaEvent {
+    SetTempo {
+        tempo: u32, // 每个四分音符的微秒数
+    },
+    TimeSignature {
+        numerator: u8,
+        denominator_power: u8, // 分母是2的幂 这里存的是指数
+        clocks_per_click: u8,
+        thirty_seconds_per_quarter: u8,
+    },
+    KeySignature {
+        sharps: i8, // 负数为降号个数 正数为升号个数
+        minor: bool,
+    },
+    Text {
+        text: Vec<u8>,
+    },
+    TrackName {
+        text: Vec<u8>,
+    },
+    Lyric {
+        text: Vec<u8>,
+    },
+    Marker {
+        text: Vec<u8>,
+    },
+    CuePoint {
+        text: Vec<u8>,
+    },
+    EndOfTrack,
+    SequencerSpecific {
+        data: Vec<u8>,
+    },
+    Unknown {
+        meta_type: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl MetaEvent {
+    // 长度不符合规范的事件按Unknown保存 保证原始数据不丢失
+    pub fn parse(meta_type: u8, data: &[u8]) -> MetaEvent {
+        match (meta_type, data.len()) {
+            (META_TEXT, _) => MetaEvent::Text {
+                text: data.to_vec(),
+            },
+            (META_TRACK_NAME, _) => MetaEvent::TrackName {
+                text: data.to_vec(),
+            },
+            (META_LYRIC, _) => MetaEvent::Lyric {
+                text: data.to_vec(),
+            },
+            (META_MARKER, _) => MetaEvent::Marker {
+                text: data.to_vec(),
+            },
+            (META_CUE_POINT, _) => MetaEvent::CuePoint {
+                text: data.to_vec(),
+            },
+            (META_END_OF_TRACK, 0) => MetaEvent::EndOfTrack,
+            (META_SET_TEMPO, 3) => MetaEvent::SetTempo {
+                tempo: u32::from_be_bytes([0, data[0], data[1], data[2]]),
+            },
+            (META_TIME_SIGNATURE, 4) => MetaEvent::TimeSignature {
+                numerator: data[0],
+                denominator_power: data[1],
+                clocks_per_click: data[2],
+                thirty_seconds_per_quarter: data[3],
+            },
+            (META_KEY_SIGNATURE, 2) => MetaEvent::KeySignature {
+                sharps: data[0] as i8,
+                minor: data[1] == 1,
+            },
+            (META_SEQUENCER_SPECIFIC, _) => MetaEvent::SequencerSpecific {
+                data: data.to_vec(),
+            },
+            _ => MetaEvent::Unknown {
+                meta_type,
+                data: data.to_vec(),
+            },
+        }
+    }
+
+    pub fn meta_type(&self) -> u8 {
+        match self {
+            MetaEvent::SetTempo { .. } => META_SET_TEMPO,
+            MetaEvent::TimeSignature { .. } => META_TIME_SIGNATURE,
+            MetaEvent::KeySignature { .. } => META_KEY_SIGNATURE,
+            MetaEvent::Text { .. } => META_TEXT,
+            MetaEvent::TrackName { .. } => META_TRACK_NAME,
+            MetaEvent::Lyric { .. } => META_LYRIC,
+            MetaEvent::Marker { .. } => META_MARKER,
+            MetaEvent::CuePoint { .. } => META_CUE_POINT,
+            MetaEvent::EndOfTrack => META_END_OF_TRACK,
+            MetaEvent::SequencerSpecific { .. } => META_SEQUENCER_SPECIFIC,
+            MetaEvent::Unknown { meta_type, .. } => *meta_type,
+        }
+    }
+
+    // 编码成meta事件的数据部分
+    pub fn data(&self) -> Vec<u8> {
+        match self {
+            MetaEvent::SetTempo { tempo } => tempo.to_be_bytes()[1..].to_vec(),
+            MetaEvent::TimeSignature {
+                numerator,
+                denominator_power,
+                clocks_per_click,
+                thirty_seconds_per_quarter,
+            } => vec![
+                *numerator,
+                *denominator_power,
+                *clocks_per_click,
+                *thirty_seconds_per_quarter,
+            ],
+            MetaEvent::KeySignature { sharps, minor } => vec![*sharps as u8, *minor as u8],
+            MetaEvent::Text { text }
+            | MetaEvent::TrackName { text }
+            | MetaEvent::Lyric { text }
+            | MetaEvent::Marker { text }
+            | MetaEvent::CuePoint { text } => text.clone(),
+            MetaEvent::EndOfTrack => Vec::new(),
+            MetaEvent::SequencerSpecific { data } | MetaEvent::Unknown { data, .. } => data.clone(),
+        }
+    }
+
+    // 文本类事件的内容 编码不一定是utf8
+    // 老的文件(尤其是.kar)多是Latin-1 不是合法utf8时按Latin-1解码
+    pub fn text(&self) -> Option<String> {
+        match self {
+            MetaEvent::Text { text }
+            | MetaEvent::TrackName { text }
+            | MetaEvent::Lyric { text }
+            | MetaEvent::Marker { text }
+            | MetaEvent::CuePoint { text } => Some(match std::str::from_utf8(text) {
+                Ok(text) => text.to_string(),
+                Err(_) => text.iter().map(|byte| *byte as char).collect(),
+            }),
+            _ => None,
+        }
+    }
+
+    // 拍号的分母
+    pub fn denominator(&self) -> Option<u32> {
+        match self {
+            MetaEvent::TimeSignature {
+                denominator_power, ..
+            } => 1u32.checked_shl(*denominator_power as u32),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum MessageEvent {
     NoteOff {
@@ -172,11 +564,6 @@ pub enum MessageEvent {
         controller: u8,
         value: u8,
     },
-    SystemMessage {
-        system_type: u8,
-        system_length: u8,
-        system_data: Vec<u8>,
-    },
     ProgramChange {
         program: u8,
     },
@@ -188,6 +575,56 @@ pub enum MessageEvent {
         value: u8,
     },
     PitchWheel {
-        value: u16, // TODO 弯音要处理两个字节的数据 一个字节的最高位 和 一个字节的最低位要扔掉
+        value: u16, // 14位的弯音值 0-16383 中间值8192表示不弯音
     },
 }
+
+impl MessageEvent {
+    pub fn is_note_on(&self) -> bool {
+        matches!(self, MessageEvent::NoteOn { velocity, .. } if velocity.bits() > 0)
+    }
+
+    // 消息的两个数据字节 只有一个数据字节的消息第二个为0
+    pub fn data(&self) -> (u8, u8) {
+        match *self {
+            MessageEvent::NoteOff { key, velocity } | MessageEvent::NoteOn { key, velocity } => {
+                (key.bits(), velocity.bits())
+            }
+            MessageEvent::Controller { controller, value } => (controller, value),
+            MessageEvent::ProgramChange { program } => (program, 0),
+            MessageEvent::ChannelAftertouch { value } => (value, 0),
+            MessageEvent::Aftertouch { key, value } => (key, value),
+            MessageEvent::PitchWheel { value } => ((value & 0x7F) as u8, (value >> 7) as u8),
+        }
+    }
+
+    // 弯音相对中间值的偏移 -8192..=8191
+    pub fn pitch_bend_offset(&self) -> Option<i16> {
+        match *self {
+            MessageEvent::PitchWheel { value } => Some(value as i16 - PITCH_WHEEL_CENTER as i16),
+            _ => None,
+        }
+    }
+
+    // 力度为0的NoteOn也是关音
+    pub fn is_note_off(&self) -> bool {
+        match self {
+            MessageEvent::NoteOff { .. } => true,
+            MessageEvent::NoteOn { velocity, .. } => velocity.bits() == 0,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 超出变长int范围的间隔不能panic 截断到最大值
+    #[test]
+    fn delta_ticks_clamped() {
+        let midi_message = MidiMessage::from_meta(u32::MAX, MetaEvent::EndOfTrack);
+        assert_eq!(midi_message.delta_ticks(), MIDI_INT_MAX);
+        assert_eq!(midi_message.m_delta_time.len(), 4);
+    }
+}
@@ -5,11 +5,25 @@ use bitflags::bitflags;
 use self::base::*;
 use self::header::*;
 use self::tracks::*;
+use self::midi_message::{Event, MetaEvent};
+use self::chunk::UnknownChunk;
+use self::rmid::{is_rmid, Rmid};
+use self::error::{MidiParseError, ParseWarning};
+use std::path::Path;
 
+pub mod bar_map;
 pub mod base;
+pub mod chunk;
+pub mod error;
 pub mod header;
+pub mod karaoke;
 pub mod tracks;
 pub mod midi_message;
+pub mod notes;
+pub mod reader;
+pub mod rmid;
+pub mod sequencer;
+pub mod tempo_map;
 
 // 只看三位
 const NOTE_OFF_VALUE: u8 = 0x00 << 4;
@@ -21,39 +35,321 @@ const CHANNEL_PRESSURE_VALUE: u8 = 0x05 << 4;
 const PITCH_WHEEL_VALUE: u8 = 0x06 << 4;
 const SYSTEM_EXCLUSIVE_VALUE: u8 = 0x07 << 4;
 
+pub const PITCH_WHEEL_CENTER: u16 = 0x2000;
+
+// 完整的状态字节
+const SYSTEM_EXCLUSIVE_STATUS: u8 = 0xF0;
+const SYSTEM_EXCLUSIVE_ESCAPE: u8 = 0xF7;
+const META_EVENT_STATUS: u8 = 0xFF;
+
+// meta事件类型
+const META_TEXT: u8 = 0x01;
+const META_TRACK_NAME: u8 = 0x03;
+const META_LYRIC: u8 = 0x05;
+const META_MARKER: u8 = 0x06;
+const META_CUE_POINT: u8 = 0x07;
+const META_END_OF_TRACK: u8 = 0x2F;
+const META_SET_TEMPO: u8 = 0x51;
+const META_TIME_SIGNATURE: u8 = 0x58;
+const META_KEY_SIGNATURE: u8 = 0x59;
+const META_SEQUENCER_SPECIFIC: u8 = 0x7F;
+
 
 const MIDI_HEADER_TRACKS_OFFSET:usize = 8;
+const MIDI_HEADER_NUM_TRACKS_OFFSET: usize = 10;
+
+// 解析选项 strict为false时尽量从损坏的文件里恢复 遇到的问题作为警告返回
+#[derive(Debug, Clone, Copy)]
+pub struct ParseOptions {
+    pub strict: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions { strict: true }
+    }
+}
+
+impl ParseOptions {
+    pub fn lenient() -> ParseOptions {
+        ParseOptions { strict: false }
+    }
+}
 
 #[derive(Debug)]
 pub struct MidiFile {
     pub header: Header,
     pub tracks: Tracks,
+    pub unknown_chunks: Vec<UnknownChunk>, // 不认识的chunk 写回时原样保留
+    pub embedded_soundfont: Option<Vec<u8>>, // RMID里带的SF2音色库
 }
 impl Parser for MidiFile {
     fn new(raw_data: &[u8]) -> MidiFile {
         MidiFile {
             header: Header::new(raw_data),
             tracks: Tracks(Vec::new()),
+            unknown_chunks: Vec::new(),
+            embedded_soundfont: None,
         }
     }
     fn get_raw(&self) -> Vec<u8> {
-        todo!()
+        self.get_raw_with(RunningStatus::Preserve)
+    }
+
+    fn parse(raw_data: &[u8]) -> Result<MidiFile, MidiParseError> {
+        MidiFile::parse_with_options(raw_data, &ParseOptions::default())
+            .map(|(midi_file, _)| midi_file)
+    }
+}
+
+impl MidiFile {
+    pub fn parse_with_options(
+        raw_data: &[u8],
+        options: &ParseOptions,
+    ) -> Result<(MidiFile, Vec<ParseWarning>), MidiParseError> {
+        let mut warnings = Vec::new();
+        // .rmi文件 解开RIFF容器后按标准MIDI文件解析 里面不能再套一层RIFF
+        if is_rmid(raw_data) {
+            let rmid = Rmid::parse_with_options(raw_data, options, &mut warnings)?;
+            let (mut midi_file, midi_warnings) = MidiFile::parse_smf(&rmid.m_midi_data, options)
+                .map_err(|err| err.at(rmid.m_data_offset))?;
+            warnings.extend(
+                midi_warnings
+                    .into_iter()
+                    .map(|warning| warning.at(rmid.m_data_offset)),
+            );
+            midi_file.embedded_soundfont = rmid.m_soundfont;
+            return Ok((midi_file, warnings));
+        }
+        MidiFile::parse_smf(raw_data, options)
     }
 
-    fn parse(raw_data: &[u8]) -> Result<MidiFile, Box<dyn Error>> {
+    // 标准MIDI文件 从MThd开始
+    fn parse_smf(
+        raw_data: &[u8],
+        options: &ParseOptions,
+    ) -> Result<(MidiFile, Vec<ParseWarning>), MidiParseError> {
         let mut midi_file = MidiFile::new(raw_data);
+        let mut warnings = Vec::new();
         let header = Header::parse(raw_data)?; // 解析头部
-        let cursor = header.m_header_size + MIDI_HEADER_TRACKS_OFFSET as u32;
-        let tracks = Tracks::parse(&raw_data[cursor as usize..])?; // 解析音轨
+        let cursor = header.m_header_size as usize + MIDI_HEADER_TRACKS_OFFSET;
+        let (tracks, unknown_chunks) = Tracks::parse_with_options(
+            raw_data.get(cursor..).unwrap_or_default(),
+            options,
+            &mut warnings,
+        )
+        .map_err(|err| err.at(cursor))?; // 解析音轨
+        let mut warnings: Vec<ParseWarning> = warnings
+            .into_iter()
+            .map(|warning| warning.at(cursor))
+            .collect();
+
+        if tracks.0.len() != header.m_num_tracks as usize {
+            if options.strict {
+                return Err(MidiParseError::TrackCountMismatch {
+                    offset: MIDI_HEADER_NUM_TRACKS_OFFSET,
+                    track: None,
+                    declared: header.m_num_tracks,
+                    found: tracks.0.len(),
+                });
+            }
+            warnings.push(ParseWarning::TrackCountMismatch {
+                offset: MIDI_HEADER_NUM_TRACKS_OFFSET,
+                declared: header.m_num_tracks,
+                found: tracks.0.len(),
+            });
+        }
         midi_file.header = header;
         midi_file.tracks = tracks;
+        midi_file.unknown_chunks = unknown_chunks;
+        Ok((midi_file, warnings))
+    }
+
+    // 头部的音轨数按实际的音轨写出
+    pub fn get_raw_with(&self, running_status: RunningStatus) -> Vec<u8> {
+        let header = Header {
+            m_num_tracks: self.tracks.0.len() as u16,
+            ..self.header.clone()
+        };
+        let mut raw_data = header.get_raw();
+        let mut unknown_chunks = self.unknown_chunks.iter().peekable();
+        for (index, track) in self.tracks.0.iter().enumerate() {
+            while let Some(unknown_chunk) =
+                unknown_chunks.next_if(|unknown_chunk| unknown_chunk.m_track_index <= index)
+            {
+                raw_data.extend(unknown_chunk.get_raw());
+            }
+            raw_data.extend(track.get_raw_with(running_status));
+        }
+        // 剩下的放在最后
+        for unknown_chunk in unknown_chunks {
+            raw_data.extend(unknown_chunk.get_raw());
+        }
+        raw_data
+    }
 
-        Ok(midi_file)
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, self.get_raw())
+    }
+
+    // 配对好的音符 按开始时间排序
+    pub fn notes(&self) -> Vec<notes::Note> {
+        notes::collect_notes(self)
+    }
+
+    // 通道消息用到的通道 从小到大
+    pub fn channels(&self) -> Vec<MidiChannel> {
+        let mut channels: Vec<MidiChannel> = self
+            .tracks
+            .0
+            .iter()
+            .flat_map(|track| track.m_midi_message.iter())
+            .filter_map(|message| message.channel())
+            .collect();
+        channels.sort();
+        channels.dedup();
+        channels
+    }
+
+    // 所有音轨中的meta事件 (音轨序号, 事件)
+    pub fn meta_events(&self) -> impl Iterator<Item = (usize, &MetaEvent)> {
+        self.tracks.0.iter().enumerate().flat_map(|(index, track)| {
+            track
+                .m_midi_message
+                .iter()
+                .filter_map(move |message| match &message.m_ment_event {
+                    Event::Meta { meta } => Some((index, meta)),
+                    _ => None,
+                })
+        })
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub fn test() {
-    let raw_data = include_bytes!("../../test_assets/sanye.mid");
-    let _midi_file: MidiFile = MidiFile::parse(raw_data).unwrap();
+    // 读入再写出必须和原文件完全一致
+    #[test]
+    fn round_trip() {
+        for raw_data in [
+            &include_bytes!("../../test_assets/sanye.mid")[..],
+            &include_bytes!("../../test_assets/CrabRave.mid")[..],
+        ] {
+            assert_eq!(MidiFile::parse(raw_data).unwrap().get_raw(), raw_data);
+        }
+    }
+
+    // 比6个字节长的头部也要原样写回
+    #[test]
+    fn round_trip_long_header() {
+        let raw_data = include_bytes!("../../test_assets/sanye.mid");
+        let mut long_header = raw_data[..4].to_vec();
+        long_header.extend_from_slice(&9u32.to_be_bytes());
+        long_header.extend_from_slice(&raw_data[8..14]);
+        long_header.extend_from_slice(&[1, 2, 3]);
+        long_header.extend_from_slice(&raw_data[14..]);
+        let midi_file = MidiFile::parse(&long_header).unwrap();
+        assert_eq!(midi_file.header.m_extra, [1, 2, 3]);
+        assert_eq!(midi_file.get_raw(), long_header);
+    }
+
+    // RMID里只能是标准MIDI文件 套娃的RIFF直接报错
+    #[test]
+    fn nested_rmid() {
+        let rmid = |midi_data: Vec<u8>| Rmid {
+            m_midi_data: midi_data,
+            m_soundfont: None,
+            m_data_offset: 0,
+        };
+        let raw_data = include_bytes!("../../test_assets/sanye.mid").to_vec();
+        let single = rmid(raw_data.clone()).get_raw();
+        assert_eq!(MidiFile::parse(&single).unwrap().get_raw(), raw_data);
+        let nested = rmid(single).get_raw();
+        assert!(matches!(
+            MidiFile::parse(&nested),
+            Err(MidiParseError::BadChunkMagic { .. })
+        ));
+    }
+
+    // 格式1 每个四分音符480tick 头部的音轨数单独给
+    fn smf(num_tracks: u16, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut raw_data = b"MThd".to_vec();
+        raw_data.extend_from_slice(&6u32.to_be_bytes());
+        raw_data.extend_from_slice(&1u16.to_be_bytes());
+        raw_data.extend_from_slice(&num_tracks.to_be_bytes());
+        raw_data.extend_from_slice(&480u16.to_be_bytes());
+        raw_data.extend(chunks.concat());
+        raw_data
+    }
+
+    fn track(data: &[u8]) -> Vec<u8> {
+        let mut raw_data = b"MTrk".to_vec();
+        raw_data.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        raw_data.extend_from_slice(data);
+        raw_data
+    }
+
+    const END_OF_TRACK: [u8; 4] = [0x00, 0xFF, 0x2F, 0x00];
+
+    // 错误的位置是相对整个文件的偏移
+    #[test]
+    fn truncated_vlq_offset() {
+        // 间隔时间的最后一个字节还带着继续的标志 音轨数据就结束了
+        let raw_data = smf(1, &[&track(&[0x81, 0x80])]);
+        assert_eq!(
+            MidiFile::parse(&raw_data).unwrap_err(),
+            MidiParseError::UnexpectedEof {
+                offset: 24,
+                track: Some(0),
+            }
+        );
+        let raw_data = smf(1, &[&track(&[0x81, 0x81, 0x81, 0x81, 0x00])]);
+        assert_eq!(
+            MidiFile::parse(&raw_data).unwrap_err(),
+            MidiParseError::InvalidVlq {
+                offset: 22,
+                track: Some(0),
+            }
+        );
+    }
+
+    #[test]
+    fn bad_chunk_magic_offset() {
+        let mut raw_data = smf(1, &[&track(&END_OF_TRACK)]);
+        raw_data[3] = b'x';
+        assert_eq!(
+            MidiFile::parse(&raw_data).unwrap_err(),
+            MidiParseError::BadChunkMagic {
+                offset: 0,
+                track: None,
+                expected: *b"MThd",
+                found: *b"MThx",
+            }
+        );
+        let raw_data = smf(2, &[&track(&END_OF_TRACK), &[0, 1, 2, 3, 0, 0, 0, 0]]);
+        assert_eq!(
+            MidiFile::parse(&raw_data).unwrap_err(),
+            MidiParseError::BadChunkMagic {
+                offset: 26,
+                track: Some(1),
+                expected: *b"MTrk",
+                found: [0, 1, 2, 3],
+            }
+        );
+    }
+
+    #[test]
+    fn track_count_mismatch_offset() {
+        let raw_data = smf(2, &[&track(&END_OF_TRACK)]);
+        assert_eq!(
+            MidiFile::parse(&raw_data).unwrap_err(),
+            MidiParseError::TrackCountMismatch {
+                offset: 10,
+                track: None,
+                declared: 2,
+                found: 1,
+            }
+        );
+    }
 }
@@ -0,0 +1,132 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::{
+    base::MidiChannel,
+    midi_message::{Event, MessageEvent},
+    MidiFile,
+};
+
+// 由NoteOn和对应的NoteOff配对得到的音符 时间都是绝对tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    pub channel: MidiChannel,
+    pub key: u8,
+    pub velocity: u8,
+    pub off_velocity: Option<u8>, // 音轨结束时还没有关掉的音符为None
+    pub start_tick: u64,
+    pub duration_ticks: u64,
+    pub track: usize,
+}
+
+// 每个音轨单独配对 力度为0的NoteOn当作NoteOff
+// 同一个通道同一个键重叠的音符按先开先关配对
+// 没有关掉的音符持续到音轨的最后一个事件
+pub fn collect_notes(midi_file: &MidiFile) -> Vec<Note> {
+    let mut notes: Vec<Note> = Vec::new();
+    for (index, track) in midi_file.tracks.0.iter().enumerate() {
+        let mut end_tick: u64 = 0;
+        let mut pending: HashMap<(MidiChannel, u8), VecDeque<Note>> = HashMap::new();
+        for (tick, message) in track.timeline() {
+            end_tick = tick;
+            let (Event::Midi { message: event }, Some(channel)) =
+                (&message.m_ment_event, message.channel())
+            else {
+                continue;
+            };
+            match *event {
+                MessageEvent::NoteOn { key, velocity } if event.is_note_on() => pending
+                    .entry((channel, key.bits()))
+                    .or_default()
+                    .push_back(Note {
+                        channel,
+                        key: key.bits(),
+                        velocity: velocity.bits(),
+                        off_velocity: None,
+                        start_tick: tick,
+                        duration_ticks: 0,
+                        track: index,
+                    }),
+                MessageEvent::NoteOff { key, velocity }
+                | MessageEvent::NoteOn { key, velocity } => {
+                    // 没有对应NoteOn的NoteOff直接忽略
+                    if let Some(mut note) = pending
+                        .get_mut(&(channel, key.bits()))
+                        .and_then(VecDeque::pop_front)
+                    {
+                        note.off_velocity = Some(velocity.bits());
+                        note.duration_ticks = tick - note.start_tick;
+                        notes.push(note);
+                    }
+                }
+                _ => {}
+            }
+        }
+        for mut note in pending.into_values().flatten() {
+            note.duration_ticks = end_tick - note.start_tick;
+            notes.push(note);
+        }
+    }
+    notes.sort_by_key(|note| (note.start_tick, note.track, note.channel, note.key));
+    notes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_format::base::Parser;
+
+    // 一个音轨的事件数据 自动补上End of Track
+    fn notes(events: &[u8]) -> Vec<Note> {
+        let mut track = events.to_vec();
+        track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+        let mut raw_data = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x01\xE0MTrk".to_vec();
+        raw_data.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        raw_data.extend(track);
+        collect_notes(&MidiFile::parse(&raw_data).unwrap())
+    }
+
+    fn note(
+        key: u8,
+        velocity: u8,
+        off_velocity: Option<u8>,
+        start_tick: u64,
+        duration_ticks: u64,
+    ) -> Note {
+        Note {
+            channel: MidiChannel::new(0).unwrap(),
+            key,
+            velocity,
+            off_velocity,
+            start_tick,
+            duration_ticks,
+            track: 0,
+        }
+    }
+
+    #[test]
+    fn zero_velocity_note_on_is_note_off() {
+        let notes = notes(&[0x00, 0x90, 60, 64, 10, 0x90, 60, 0]);
+        assert_eq!(notes, [note(60, 64, Some(0), 0, 10)]);
+    }
+
+    #[test]
+    fn overlapping_notes_pair_first_in_first_out() {
+        let notes = notes(&[
+            0x00, 0x90, 62, 10, // tick 0
+            5, 0x90, 62, 20, // tick 5
+            5, 0x80, 62, 30, // tick 10
+            10, 0x80, 62, 40, // tick 20
+        ]);
+        assert_eq!(
+            notes,
+            [note(62, 10, Some(30), 0, 10), note(62, 20, Some(40), 5, 15)]
+        );
+    }
+
+    // 没有关掉的音符持续到End of Track
+    #[test]
+    fn unterminated_note_lasts_to_end_of_track() {
+        let notes = notes(&[0x00, 0x90, 64, 50, 30, 0xB0, 7, 100]);
+        assert_eq!(notes, [note(64, 50, None, 0, 30)]);
+    }
+}
@@ -0,0 +1,355 @@
+use std::io::{self, BufReader, Read};
+
+use super::{
+    base::{MidiStatusByte, Parser},
+    chunk::{ChunkHeader, CHUNK_HEADER_SIZE},
+    error::MidiParseError,
+    header::{Header, HEADER_SIZE},
+    midi_message::{channel_data_size, Event, MetaEvent, MidiMessage},
+    META_EVENT_STATUS, MIDI_HEADER_NUM_TRACKS_OFFSET, SYSTEM_EXCLUSIVE_ESCAPE,
+    SYSTEM_EXCLUSIVE_STATUS,
+};
+
+// 流式读到的一个事件 m_tick是在所在音轨里的绝对tick
+#[derive(Debug, Clone)]
+pub struct TrackEvent {
+    pub m_track: usize,
+    pub m_tick: u64,
+    pub m_message: MidiMessage,
+}
+
+// 正在读的音轨
+struct TrackState {
+    m_index: usize,
+    m_remaining: usize, // 音轨里还没读的字节数
+    m_tick: u64,
+    m_pre_status: Option<MidiStatusByte>,
+}
+
+// 从io::Read里按顺序一个一个读取事件 不需要把整个文件读进内存
+// 同时只保存一个事件的数据 适合很大的文件或者从管道读入的数据
+// 和MidiFile::parse的严格模式一样检查数据 不认识的chunk直接跳过
+pub struct MidiReader<R: Read> {
+    m_reader: BufReader<R>,
+    m_header: Header,
+    m_offset: usize, // 已经读过的字节数 用于错误信息
+    m_track: Option<TrackState>,
+    m_track_count: usize,
+    m_buffer: Vec<u8>, // 当前事件的原始数据 每次重复使用
+    m_finished: bool,
+}
+
+impl<R: Read> MidiReader<R> {
+    // 读入并检查头部
+    pub fn new(reader: R) -> Result<MidiReader<R>, MidiParseError> {
+        let mut midi_reader = MidiReader {
+            m_reader: BufReader::new(reader),
+            m_header: Header::new(&[]),
+            m_offset: 0,
+            m_track: None,
+            m_track_count: 0,
+            m_buffer: Vec::new(),
+            m_finished: false,
+        };
+        let mut raw_data = [0; HEADER_SIZE];
+        midi_reader.read_exact(&mut raw_data)?;
+        midi_reader.m_header = Header::parse(&raw_data)?;
+        // 头部比6个字节长的部分
+        let extra = midi_reader.m_header.m_header_size as usize + CHUNK_HEADER_SIZE - HEADER_SIZE;
+        let mut raw_data = Vec::new();
+        midi_reader.read_append(&mut raw_data, extra)?;
+        midi_reader.m_header.m_extra = raw_data;
+        Ok(midi_reader)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.m_header
+    }
+
+    // 读取下一个事件 所有音轨都读完后返回None
+    pub fn next_event(&mut self) -> Result<Option<TrackEvent>, MidiParseError> {
+        while !self.m_finished {
+            let Some(mut track) = self.m_track.take() else {
+                self.next_track()?;
+                continue;
+            };
+            let index = track.m_index;
+            let midi_message = self
+                .read_message(&mut track)
+                .map_err(|err| err.in_track(index))?;
+            track.m_tick += midi_message.delta_ticks() as u64;
+            let tick = track.m_tick;
+            if midi_message.is_channel_message() {
+                track.m_pre_status = Some(midi_message.m_status);
+            }
+            let end_of_track = matches!(
+                midi_message.m_ment_event,
+                Event::Meta {
+                    meta: MetaEvent::EndOfTrack
+                }
+            );
+            // End of Track之后剩下的字节忽略 音轨读完时不需要End of Track
+            if end_of_track {
+                self.skip_bytes(track.m_remaining)
+                    .map_err(|err| err.in_track(index))?;
+            } else if track.m_remaining > 0 {
+                self.m_track = Some(track);
+            }
+            return Ok(Some(TrackEvent {
+                m_track: index,
+                m_tick: tick,
+                m_message: midi_message,
+            }));
+        }
+        Ok(None)
+    }
+
+    // 读取下一个chunk的头 是MTrk时开始读这个音轨 其他chunk跳过
+    fn next_track(&mut self) -> Result<(), MidiParseError> {
+        let mut raw_data = [0; CHUNK_HEADER_SIZE];
+        let offset = self.m_offset;
+        let read = self.read_bytes(&mut raw_data)?;
+        if read == 0 {
+            self.m_finished = true;
+            if self.m_track_count != self.m_header.m_num_tracks as usize {
+                return Err(MidiParseError::TrackCountMismatch {
+                    offset: MIDI_HEADER_NUM_TRACKS_OFFSET,
+                    track: None,
+                    declared: self.m_header.m_num_tracks,
+                    found: self.m_track_count,
+                });
+            }
+            return Ok(());
+        }
+        let chunk_header = ChunkHeader::parse(&raw_data[..read])
+            .map_err(|err| err.at(offset).in_track(self.m_track_count))?;
+        if !chunk_header.is_valid() {
+            return Err(MidiParseError::BadChunkMagic {
+                offset,
+                track: Some(self.m_track_count),
+                expected: *b"MTrk",
+                found: chunk_header.m_magic,
+            });
+        }
+        if chunk_header.m_magic != *b"MTrk" {
+            return self.skip_bytes(chunk_header.m_size as usize);
+        }
+        self.m_track = Some(TrackState {
+            m_index: self.m_track_count,
+            m_remaining: chunk_header.m_size as usize,
+            m_tick: 0,
+            m_pre_status: None,
+        });
+        self.m_track_count += 1;
+        Ok(())
+    }
+
+    // 先按格式读出一个事件的全部字节 再交给MidiMessage::parse解析
+    fn read_message(&mut self, track: &mut TrackState) -> Result<MidiMessage, MidiParseError> {
+        let offset = self.m_offset;
+        let mut raw_data = std::mem::take(&mut self.m_buffer);
+        raw_data.clear();
+        let result = self.read_message_bytes(track, &mut raw_data).and_then(|_| {
+            MidiMessage::parse(&raw_data, &track.m_pre_status).map_err(|err| err.at(offset))
+        });
+        self.m_buffer = raw_data;
+        result
+    }
+
+    fn read_message_bytes(
+        &mut self,
+        track: &mut TrackState,
+        raw_data: &mut Vec<u8>,
+    ) -> Result<(), MidiParseError> {
+        self.read_midi_int(track, raw_data)?; // delta time
+        let status = self.read_track_bytes(track, raw_data, 1)?[0];
+        match status {
+            META_EVENT_STATUS => {
+                self.read_track_bytes(track, raw_data, 1)?; // meta类型
+                let length = self.read_midi_int(track, raw_data)?;
+                self.read_track_bytes(track, raw_data, length as usize)?;
+            }
+            SYSTEM_EXCLUSIVE_STATUS | SYSTEM_EXCLUSIVE_ESCAPE => {
+                let length = self.read_midi_int(track, raw_data)?;
+                self.read_track_bytes(track, raw_data, length as usize)?;
+            }
+            // running status时第一个字节已经是数据了
+            _ if status & 0x80 == 0 => {
+                if let Some(data_size) = track.m_pre_status.and_then(channel_data_size) {
+                    self.read_track_bytes(track, raw_data, data_size - 1)?;
+                }
+            }
+            _ => {
+                let status = MidiStatusByte::from_bits_retain(status);
+                if let Some(data_size) = channel_data_size(status) {
+                    self.read_track_bytes(track, raw_data, data_size)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 读取一个变长int 原始字节追加到raw_data 返回它的值
+    fn read_midi_int(
+        &mut self,
+        track: &mut TrackState,
+        raw_data: &mut Vec<u8>,
+    ) -> Result<u32, MidiParseError> {
+        let offset = self.m_offset;
+        let mut value: u32 = 0;
+        for _ in 0..4 {
+            let byte = self.read_track_bytes(track, raw_data, 1)?[0];
+            value = value << 7 | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MidiParseError::InvalidVlq {
+            offset,
+            track: None,
+        })
+    }
+
+    // 在音轨范围内读取length个字节追加到raw_data 返回读到的部分
+    fn read_track_bytes<'a>(
+        &mut self,
+        track: &mut TrackState,
+        raw_data: &'a mut Vec<u8>,
+        length: usize,
+    ) -> Result<&'a [u8], MidiParseError> {
+        if length > track.m_remaining {
+            return Err(MidiParseError::eof(self.m_offset + track.m_remaining));
+        }
+        let start = raw_data.len();
+        self.read_append(raw_data, length)?;
+        track.m_remaining -= length;
+        Ok(&raw_data[start..])
+    }
+
+    // 尽量读满buffer 返回实际读到的字节数 只有数据结束时才会读不满
+    fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<usize, MidiParseError> {
+        let mut read = 0;
+        while read < buffer.len() {
+            match self.m_reader.read(&mut buffer[read..]) {
+                Ok(0) => break,
+                Ok(size) => read += size,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    return Err(MidiParseError::Io {
+                        offset: self.m_offset + read,
+                        track: None,
+                        kind: err.kind(),
+                    })
+                }
+            }
+        }
+        self.m_offset += read;
+        Ok(read)
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), MidiParseError> {
+        if self.read_bytes(buffer)? < buffer.len() {
+            return Err(MidiParseError::eof(self.m_offset));
+        }
+        Ok(())
+    }
+
+    // 读取length个字节追加到raw_data 边读边分配 不会因为声明的长度太大一次分配很多内存
+    fn read_append(&mut self, raw_data: &mut Vec<u8>, length: usize) -> Result<(), MidiParseError> {
+        let read = (&mut self.m_reader)
+            .take(length as u64)
+            .read_to_end(raw_data)
+            .map_err(|err| MidiParseError::Io {
+                offset: self.m_offset,
+                track: None,
+                kind: err.kind(),
+            })?;
+        self.m_offset += read;
+        if read < length {
+            return Err(MidiParseError::eof(self.m_offset));
+        }
+        Ok(())
+    }
+
+    fn skip_bytes(&mut self, length: usize) -> Result<(), MidiParseError> {
+        let skipped = io::copy(
+            &mut (&mut self.m_reader).take(length as u64),
+            &mut io::sink(),
+        )
+        .map_err(|err| MidiParseError::Io {
+            offset: self.m_offset,
+            track: None,
+            kind: err.kind(),
+        })? as usize;
+        self.m_offset += skipped;
+        if skipped < length {
+            return Err(MidiParseError::eof(self.m_offset));
+        }
+        Ok(())
+    }
+}
+
+// 出错之后不再继续读
+impl<R: Read> Iterator for MidiReader<R> {
+    type Item = Result<TrackEvent, MidiParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.next_event();
+        if result.is_err() {
+            self.m_finished = true;
+        }
+        result.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_format::MidiFile;
+
+    // 和一次读入整个文件的结果一样
+    #[test]
+    fn same_as_parse() {
+        for raw_data in [
+            &include_bytes!("../../test_assets/sanye.mid")[..],
+            &include_bytes!("../../test_assets/CrabRave.mid")[..],
+        ] {
+            let midi_file = MidiFile::parse(raw_data).unwrap();
+            let expected: Vec<(usize, u64, Vec<u8>)> = midi_file
+                .tracks
+                .0
+                .iter()
+                .enumerate()
+                .flat_map(|(index, track)| {
+                    track
+                        .timeline()
+                        .map(move |(tick, message)| (index, tick, message.get_raw()))
+                })
+                .collect();
+            let events: Vec<(usize, u64, Vec<u8>)> = MidiReader::new(raw_data)
+                .unwrap()
+                .map(|event| {
+                    let event = event.unwrap();
+                    (event.m_track, event.m_tick, event.m_message.get_raw())
+                })
+                .collect();
+            assert_eq!(events, expected);
+        }
+    }
+
+    // 声明了很长的meta事件但是数据不够 报错而不是先分配声明的长度
+    #[test]
+    fn truncated_long_event() {
+        let mut raw_data = include_bytes!("../../test_assets/sanye.mid")[..14].to_vec();
+        raw_data[10..12].copy_from_slice(&1u16.to_be_bytes());
+        raw_data.extend_from_slice(b"MTrk");
+        raw_data.extend_from_slice(&u32::MAX.to_be_bytes());
+        raw_data.extend_from_slice(&[0x00, 0xFF, 0x01, 0xFF, 0xFF, 0xFF, 0x7F, b'a']);
+        let mut reader = MidiReader::new(&raw_data[..]).unwrap();
+        assert!(matches!(
+            reader.next_event(),
+            Err(MidiParseError::UnexpectedEof { .. })
+        ));
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,184 @@
+use super::{
+    base::Parser,
+    error::{MidiParseError, ParseWarning},
+    ParseOptions,
+};
+
+const RIFF_HEADER_SIZE: usize = 12;
+const RIFF_CHUNK_HEADER_SIZE: usize = 8;
+
+// RIFF RMID容器(.rmi) "RIFF" 长度 "RMID" 后面跟着若干子chunk
+// 和SMF不同 RIFF的长度是小端的 每个chunk按偶数字节对齐
+// "data"里是完整的标准MIDI文件 有的文件还带一个RIFF sfbk(SF2)或者RIFF DLS音色库
+#[derive(Debug)]
+pub struct Rmid {
+    pub m_midi_data: Vec<u8>,
+    pub m_soundfont: Option<Vec<u8>>, // 完整的SF2文件 包括RIFF头
+    pub m_data_offset: usize,         // MIDI数据在容器里的位置 用来修正错误的偏移
+}
+
+pub fn is_rmid(raw_data: &[u8]) -> bool {
+    raw_data.len() >= RIFF_HEADER_SIZE && raw_data[..4] == *b"RIFF" && raw_data[8..12] == *b"RMID"
+}
+
+impl Parser for Rmid {
+    fn new(_: &[u8]) -> Rmid {
+        Rmid {
+            m_midi_data: Vec::new(),
+            m_soundfont: None,
+            m_data_offset: 0,
+        }
+    }
+
+    fn parse(raw_data: &[u8]) -> Result<Rmid, MidiParseError> {
+        Rmid::parse_with_options(raw_data, &ParseOptions::default(), &mut Vec::new())
+    }
+
+    fn get_raw(&self) -> Vec<u8> {
+        let mut chunks: Vec<u8> = Vec::new();
+        chunks.extend_from_slice(b"RMID");
+        write_riff_chunk(&mut chunks, b"data", &self.m_midi_data);
+        // SF2本身就是一个完整的RIFF chunk 直接追加
+        if let Some(soundfont) = &self.m_soundfont {
+            chunks.extend_from_slice(soundfont);
+            if soundfont.len() % 2 == 1 {
+                chunks.push(0);
+            }
+        }
+        let mut raw_data: Vec<u8> = Vec::with_capacity(chunks.len() + RIFF_CHUNK_HEADER_SIZE);
+        write_riff_chunk(&mut raw_data, b"RIFF", &chunks);
+        raw_data
+    }
+}
+
+impl Rmid {
+    pub fn parse_with_options(
+        raw_data: &[u8],
+        options: &ParseOptions,
+        warnings: &mut Vec<ParseWarning>,
+    ) -> Result<Rmid, MidiParseError> {
+        if !is_rmid(raw_data) {
+            return Err(MidiParseError::MissingChunk {
+                offset: 0,
+                track: None,
+                magic: *b"RMID",
+            });
+        }
+        let mut rmid = Rmid::new(raw_data);
+        // 不少写文件的程序把RIFF的长度写错 超出文件的部分直接忽略
+        let riff_end =
+            (RIFF_CHUNK_HEADER_SIZE + riff_size(&raw_data[4..]) as usize).min(raw_data.len());
+        let mut midi_data: Option<(usize, &[u8])> = None;
+        let mut cursor = RIFF_HEADER_SIZE;
+        while cursor + RIFF_CHUNK_HEADER_SIZE <= riff_end {
+            let chunk_id: [u8; 4] = raw_data[cursor..cursor + 4].try_into().unwrap();
+            let chunk_end =
+                cursor + RIFF_CHUNK_HEADER_SIZE + riff_size(&raw_data[cursor + 4..]) as usize;
+            if chunk_end > riff_end && options.strict {
+                return Err(MidiParseError::eof(riff_end));
+            }
+            let chunk_end = chunk_end.min(riff_end);
+            let body = &raw_data[cursor + RIFF_CHUNK_HEADER_SIZE..chunk_end];
+            // 嵌套的RIFF chunk 开头4个字节是它的类型
+            match (&chunk_id, body.get(..4)) {
+                (b"data", _) if midi_data.is_none() => {
+                    midi_data = Some((cursor + RIFF_CHUNK_HEADER_SIZE, body))
+                }
+                (b"RIFF", Some(b"sfbk")) => {
+                    rmid.m_soundfont = Some(raw_data[cursor..chunk_end].to_vec())
+                }
+                // DLS音色库 合成器不支持 继续使用配置的音色库
+                (b"RIFF", Some(b"DLS ")) | (b"DLS ", _) => {
+                    warnings.push(ParseWarning::UnsupportedSoundBank {
+                        offset: cursor,
+                        kind: *b"DLS ",
+                    })
+                }
+                _ => {}
+            }
+            cursor = chunk_end + (chunk_end - cursor) % 2;
+        }
+        let (m_data_offset, data) = midi_data.ok_or(MidiParseError::MissingChunk {
+            offset: RIFF_HEADER_SIZE,
+            track: None,
+            magic: *b"data",
+        })?;
+        rmid.m_midi_data = data.to_vec();
+        rmid.m_data_offset = m_data_offset;
+        Ok(rmid)
+    }
+}
+
+// RIFF的长度字段是小端的 调用前要保证至少有4个字节
+fn riff_size(raw_data: &[u8]) -> u32 {
+    u32::from_le_bytes(raw_data[..4].try_into().unwrap())
+}
+
+fn write_riff_chunk(raw_data: &mut Vec<u8>, chunk_id: &[u8; 4], data: &[u8]) {
+    raw_data.extend_from_slice(chunk_id);
+    raw_data.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    raw_data.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        raw_data.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_format::MidiFile;
+
+    const MIDI_DATA: &[u8] = include_bytes!("../../test_assets/sanye.mid");
+
+    // "RIFF" 长度 "RMID" 加上传入的子chunk
+    fn riff(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut body = b"RMID".to_vec();
+        for (chunk_id, data) in chunks {
+            write_riff_chunk(&mut body, chunk_id, data);
+        }
+        let mut raw_data = Vec::new();
+        write_riff_chunk(&mut raw_data, b"RIFF", &body);
+        raw_data
+    }
+
+    #[test]
+    fn plain_rmid() {
+        let raw_data = riff(&[(b"LIST", b"INFOabc"), (b"data", MIDI_DATA)]);
+        let rmid = Rmid::parse(&raw_data).unwrap();
+        assert_eq!(rmid.m_midi_data, MIDI_DATA);
+        // LIST有7个字节 补齐到8个
+        assert_eq!(rmid.m_data_offset, RIFF_HEADER_SIZE + 8 + 8 + 8);
+        assert!(rmid.m_soundfont.is_none());
+        assert!(MidiFile::parse(&raw_data).is_ok());
+    }
+
+    // data声明的长度超出文件 严格模式报错 宽松模式用剩下的数据
+    #[test]
+    fn truncated_riff() {
+        let mut raw_data = riff(&[(b"data", MIDI_DATA)]);
+        raw_data.truncate(raw_data.len() - 10);
+        assert_eq!(
+            Rmid::parse(&raw_data).unwrap_err(),
+            MidiParseError::UnexpectedEof {
+                offset: raw_data.len(),
+                track: None,
+            }
+        );
+        let rmid =
+            Rmid::parse_with_options(&raw_data, &ParseOptions::lenient(), &mut Vec::new()).unwrap();
+        assert_eq!(rmid.m_midi_data, raw_data[RIFF_HEADER_SIZE + 8..]);
+    }
+
+    #[test]
+    fn missing_data_chunk() {
+        let raw_data = riff(&[(b"LIST", b"INFO")]);
+        assert_eq!(
+            Rmid::parse(&raw_data).unwrap_err(),
+            MidiParseError::MissingChunk {
+                offset: RIFF_HEADER_SIZE,
+                track: None,
+                magic: *b"data",
+            }
+        );
+    }
+}
@@ -0,0 +1,40 @@
+use super::{
+    midi_message::{Event, MidiMessage},
+    MidiFile,
+};
+
+// 合并后的事件 m_tick是绝对tick
+#[derive(Debug, Clone, Copy)]
+pub struct SequencedEvent<'a> {
+    pub m_tick: u64,
+    pub m_track: usize, // 事件来自哪个音轨
+    pub m_message: &'a MidiMessage,
+}
+
+impl SequencedEvent<'_> {
+    // 同一时刻的事件 先关音 再处理其他事件 最后开音
+    fn order(&self) -> u8 {
+        match &self.m_message.m_ment_event {
+            Event::Midi { message } if message.is_note_off() => 0,
+            Event::Midi { message } if message.is_note_on() => 2,
+            _ => 1,
+        }
+    }
+}
+
+// 把所有音轨转换成绝对tick 然后合并成一个按时间排序的事件流
+// 同一时刻同一类的事件保持音轨顺序和音轨内的顺序
+pub fn merge_tracks(midi_file: &MidiFile) -> Vec<SequencedEvent<'_>> {
+    let mut events: Vec<SequencedEvent> = Vec::new();
+    for (index, track) in midi_file.tracks.0.iter().enumerate() {
+        for (tick, message) in track.timeline() {
+            events.push(SequencedEvent {
+                m_tick: tick,
+                m_track: index,
+                m_message: message,
+            });
+        }
+    }
+    events.sort_by_key(|event| (event.m_tick, event.order()));
+    events
+}
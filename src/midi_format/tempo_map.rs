@@ -0,0 +1,153 @@
+use super::{
+    header::TimeDivision,
+    midi_message::{Event, MetaEvent},
+    MidiFile,
+};
+
+const DEFAULT_TEMPO: u32 = 500_000; // 没有SetTempo事件时默认120BPM
+
+// 速度变化点 从m_tick开始使用m_tempo
+#[derive(Debug, Clone, Copy)]
+struct TempoSegment {
+    m_tick: u64,   // 开始的tick
+    m_micros: u64, // 开始的时间(微秒)
+    m_tempo: u32,  // 每个四分音符的微秒数
+}
+
+// 速度表 用来把绝对tick转换成微秒 或者反过来
+// SMPTE时间分辨率下tick的长度是固定的 速度事件不影响时间
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    m_time_division: TimeDivision,
+    m_ticks_per_quarter: u64,
+    m_segments: Vec<TempoSegment>,
+}
+
+impl TempoMap {
+    // 收集所有音轨里的SetTempo事件
+    pub fn new(midi_file: &MidiFile) -> TempoMap {
+        let mut tempos: Vec<(u64, u32)> = Vec::new();
+        for track in midi_file.tracks.0.iter() {
+            for (tick, event) in track.events() {
+                if let Event::Meta {
+                    meta: MetaEvent::SetTempo { tempo },
+                } = *event
+                {
+                    tempos.push((tick, tempo));
+                }
+            }
+        }
+        tempos.sort_by_key(|(tick, _)| *tick);
+        TempoMap::from_tempos(midi_file.header.m_time_division, &tempos)
+    }
+
+    // tempos 需要按tick排好序 同一个tick上有多个速度时 以最后一个为准
+    pub fn from_tempos(time_division: TimeDivision, tempos: &[(u64, u32)]) -> TempoMap {
+        let ticks_per_quarter = match time_division {
+            TimeDivision::TicksPerQuarter(ticks) => ticks,
+            TimeDivision::Smpte { .. } => 0,
+        };
+        let mut tempo_map = TempoMap {
+            m_time_division: time_division,
+            m_ticks_per_quarter: ticks_per_quarter.max(1) as u64,
+            m_segments: vec![TempoSegment {
+                m_tick: 0,
+                m_micros: 0,
+                m_tempo: DEFAULT_TEMPO,
+            }],
+        };
+        for &(tick, tempo) in tempos.iter() {
+            let micros = tempo_map.ticks_to_micros(tick);
+            let last = tempo_map.m_segments.last_mut().unwrap();
+            if last.m_tick == tick {
+                last.m_tempo = tempo;
+            } else {
+                tempo_map.m_segments.push(TempoSegment {
+                    m_tick: tick,
+                    m_micros: micros,
+                    m_tempo: tempo,
+                });
+            }
+        }
+        tempo_map
+    }
+
+    pub fn ticks_to_micros(&self, tick: u64) -> u64 {
+        if let Some((ticks, seconds)) = self.m_time_division.smpte_ticks_per_second() {
+            return mul_div(tick, seconds * 1_000_000, ticks.max(1));
+        }
+        let index = self
+            .m_segments
+            .partition_point(|segment| segment.m_tick <= tick)
+            - 1;
+        let segment = &self.m_segments[index];
+        segment.m_micros.saturating_add(mul_div(
+            tick - segment.m_tick,
+            segment.m_tempo as u64,
+            self.m_ticks_per_quarter,
+        ))
+    }
+
+    pub fn micros_to_ticks(&self, micros: u64) -> u64 {
+        if let Some((ticks, seconds)) = self.m_time_division.smpte_ticks_per_second() {
+            return mul_div(micros, ticks, seconds * 1_000_000);
+        }
+        let index = self
+            .m_segments
+            .partition_point(|segment| segment.m_micros <= micros)
+            - 1;
+        let segment = &self.m_segments[index];
+        segment.m_tick.saturating_add(mul_div(
+            micros - segment.m_micros,
+            self.m_ticks_per_quarter,
+            segment.m_tempo.max(1) as u64,
+        ))
+    }
+
+    // tick所在位置的速度
+    pub fn tempo_at(&self, tick: u64) -> u32 {
+        let index = self
+            .m_segments
+            .partition_point(|segment| segment.m_tick <= tick)
+            - 1;
+        self.m_segments[index].m_tempo
+    }
+}
+
+// value * mul / div 中间结果用u128 超出u64时取最大值
+pub fn mul_div(value: u64, mul: u64, div: u64) -> u64 {
+    (value as u128 * mul as u128 / div as u128).min(u64::MAX as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tempo_changes() {
+        // 480 tick每拍 第二拍开始变成每拍1秒
+        let tempo_map =
+            TempoMap::from_tempos(TimeDivision::TicksPerQuarter(480), &[(480, 1_000_000)]);
+        assert_eq!(tempo_map.ticks_to_micros(480), 500_000);
+        assert_eq!(tempo_map.ticks_to_micros(720), 1_000_000);
+        assert_eq!(tempo_map.micros_to_ticks(1_000_000), 720);
+        assert_eq!(tempo_map.tempo_at(479), DEFAULT_TEMPO);
+    }
+
+    // 很大的tick和时间不会溢出
+    #[test]
+    fn saturates() {
+        let tempo_map = TempoMap::from_tempos(TimeDivision::TicksPerQuarter(1), &[]);
+        assert_eq!(tempo_map.ticks_to_micros(u64::MAX), u64::MAX);
+        assert_eq!(tempo_map.micros_to_ticks(u64::MAX), u64::MAX / 500_000);
+        let smpte = TempoMap::from_tempos(
+            TimeDivision::Smpte {
+                frames_per_second: 25,
+                ticks_per_frame: 40,
+            },
+            &[],
+        );
+        assert_eq!(smpte.ticks_to_micros(1000), 1_000_000);
+        assert_eq!(smpte.ticks_to_micros(u64::MAX), u64::MAX);
+    }
+}
@@ -1,8 +1,13 @@
-use super::{midi_message::MidiMessage, Parser};
-use std::error::Error;
+use std::slice;
+
+use super::{
+    base::{RunningStatus, MIDI_INT_MAX},
+    chunk::{is_chunk_start, ChunkHeader, UnknownChunk, CHUNK_HEADER_SIZE},
+    error::{MidiParseError, ParseWarning},
+    midi_message::{Event, MetaEvent, MidiMessage},
+    ParseOptions, Parser,
+};
 
-const TRACK_MAGIC_SIZE: u32 = 4;
-const TRACK_SECLEN_SIZE: u32 = 4;
 #[derive(Debug)]
 pub struct Tracks(pub Vec<MidiTrack>);
 impl Parser for Tracks {
@@ -11,21 +16,84 @@ impl Parser for Tracks {
     }
 
     fn get_raw(&self) -> Vec<u8> {
-        todo!()
+        self.get_raw_with(RunningStatus::Preserve)
     }
 
-    fn parse(raw_data: &[u8]) -> Result<Tracks, Box<dyn Error>> {
+    fn parse(raw_data: &[u8]) -> Result<Tracks, MidiParseError> {
+        Tracks::parse_with_options(raw_data, &ParseOptions::default(), &mut Vec::new())
+            .map(|(tracks, _)| tracks)
+    }
+}
+
+impl Tracks {
+    // 逐个读取头部之后的chunk MTrk解析成音轨 其他的chunk原样保存
+    pub fn parse_with_options(
+        raw_data: &[u8],
+        options: &ParseOptions,
+        warnings: &mut Vec<ParseWarning>,
+    ) -> Result<(Tracks, Vec<UnknownChunk>), MidiParseError> {
         let mut tracks = Vec::new();
+        let mut unknown_chunks = Vec::new();
         let mut cursor = 0;
-        loop {
-            let track = MidiTrack::parse(&raw_data[cursor as usize..])?;
-            cursor += track.m_track_size + TRACK_MAGIC_SIZE + TRACK_SECLEN_SIZE;
-            tracks.push(track);
-            if cursor >= raw_data.len() as u32 {
-                break;
+        while cursor < raw_data.len() {
+            let chunk = &raw_data[cursor..];
+            let chunk_header = match ChunkHeader::parse(chunk) {
+                Ok(chunk_header) if chunk_header.is_valid() => chunk_header,
+                // 剩下的数据不像一个chunk 当作文件末尾的垃圾数据
+                _ if !options.strict => {
+                    warnings.push(ParseWarning::TrailingData {
+                        offset: cursor,
+                        size: chunk.len(),
+                    });
+                    break;
+                }
+                Ok(chunk_header) => {
+                    return Err(MidiParseError::BadChunkMagic {
+                        offset: cursor,
+                        track: Some(tracks.len()),
+                        expected: *b"MTrk",
+                        found: chunk_header.m_magic,
+                    })
+                }
+                Err(err) => return Err(err.at(cursor).in_track(tracks.len())),
+            };
+            if chunk_header.m_magic != *b"MTrk" {
+                let mut unknown_chunk = match UnknownChunk::parse(chunk) {
+                    Ok(unknown_chunk) => unknown_chunk,
+                    Err(_) if !options.strict => {
+                        warnings.push(ParseWarning::TrailingData {
+                            offset: cursor,
+                            size: chunk.len(),
+                        });
+                        break;
+                    }
+                    Err(err) => return Err(err.at(cursor)),
+                };
+                unknown_chunk.m_track_index = tracks.len();
+                unknown_chunks.push(unknown_chunk);
+                cursor += chunk_header.end();
+                continue;
             }
+            let mut track_warnings = Vec::new();
+            let (track, chunk_length) =
+                MidiTrack::parse_with_options(chunk, options, &mut track_warnings)
+                    .map_err(|err| err.at(cursor).in_track(tracks.len()))?;
+            warnings.extend(
+                track_warnings
+                    .into_iter()
+                    .map(|warning| warning.at(cursor).in_track(tracks.len())),
+            );
+            cursor += chunk_length;
+            tracks.push(track);
         }
-        Ok(Tracks(tracks))
+        Ok((Tracks(tracks), unknown_chunks))
+    }
+
+    pub fn get_raw_with(&self, running_status: RunningStatus) -> Vec<u8> {
+        self.0
+            .iter()
+            .flat_map(|track| track.get_raw_with(running_status))
+            .collect()
     }
 }
 #[derive(Debug)]
@@ -44,39 +112,435 @@ impl Parser for MidiTrack {
     }
 
     fn get_raw(&self) -> Vec<u8> {
-        todo!()
+        self.get_raw_with(RunningStatus::Preserve)
+    }
+
+    fn parse(raw_data: &[u8]) -> Result<MidiTrack, MidiParseError> {
+        MidiTrack::parse_with_options(raw_data, &ParseOptions::default(), &mut Vec::new())
+            .map(|(midi_track, _)| midi_track)
+    }
+}
+
+// 按绝对tick遍历音轨里的消息
+pub struct TrackTimeline<'a> {
+    m_messages: slice::Iter<'a, MidiMessage>,
+    m_tick: u64,
+}
+
+impl<'a> Iterator for TrackTimeline<'a> {
+    type Item = (u64, &'a MidiMessage);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let midi_message = self.m_messages.next()?;
+        self.m_tick += midi_message.delta_ticks() as u64;
+        Some((self.m_tick, midi_message))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.m_messages.size_hint()
+    }
+}
+
+impl MidiTrack {
+    // (绝对tick, 消息)
+    pub fn timeline(&self) -> TrackTimeline<'_> {
+        TrackTimeline {
+            m_messages: self.m_midi_message.iter(),
+            m_tick: 0,
+        }
     }
 
-    fn parse(raw_data: &[u8]) -> Result<MidiTrack, Box<dyn Error>> {
+    // (绝对tick, 事件)
+    pub fn events(&self) -> impl Iterator<Item = (u64, &Event)> {
+        self.timeline()
+            .map(|(tick, midi_message)| (tick, &midi_message.m_ment_event))
+    }
+
+    // 用绝对tick的消息构建音轨 间隔时间重新计算
+    // 按tick排序 同一tick保持传入的顺序 原有的End of Track去掉 在最后一个事件处补一个
+    // 间隔超出变长int的范围时 中间插入空的Marker事件把间隔分开
+    pub fn from_absolute<I>(events: I) -> MidiTrack
+    where
+        I: IntoIterator<Item = (u64, MidiMessage)>,
+    {
+        let mut events: Vec<(u64, MidiMessage)> = events
+            .into_iter()
+            .filter(|(_, midi_message)| {
+                !matches!(
+                    midi_message.m_ment_event,
+                    Event::Meta {
+                        meta: MetaEvent::EndOfTrack
+                    }
+                )
+            })
+            .collect();
+        events.sort_by_key(|(tick, _)| *tick);
+        let end_tick = events.last().map_or(0, |(tick, _)| *tick);
+        events.push((end_tick, MidiMessage::from_meta(0, MetaEvent::EndOfTrack)));
+
+        let mut pre_tick: u64 = 0;
+        let mut midi_track = MidiTrack::new(&[]);
+        midi_track.m_magic = *b"MTrk";
+        for (tick, mut midi_message) in events {
+            while tick - pre_tick > MIDI_INT_MAX as u64 {
+                pre_tick += MIDI_INT_MAX as u64;
+                midi_track.m_midi_message.push(MidiMessage::from_meta(
+                    MIDI_INT_MAX,
+                    MetaEvent::Marker { text: Vec::new() },
+                ));
+            }
+            midi_message.set_delta_ticks((tick - pre_tick) as u32);
+            midi_track.m_midi_message.push(midi_message);
+            pre_tick = tick;
+        }
+        midi_track
+    }
+
+    // 返回音轨和这个chunk实际占用的字节数 宽松模式下可能和声明的长度不同
+    pub fn parse_with_options(
+        raw_data: &[u8],
+        options: &ParseOptions,
+        warnings: &mut Vec<ParseWarning>,
+    ) -> Result<(MidiTrack, usize), MidiParseError> {
         let mut midi_track = MidiTrack::new(raw_data);
         let mut cursor = 0;
 
-        let m_magic = raw_data[cursor..TRACK_MAGIC_SIZE as usize].try_into()?; // MIDI头部标识 值为"MThd"或者 "MTrk"
-        cursor += TRACK_MAGIC_SIZE as usize;
-        let m_track_size = u32::from_be_bytes(
-            raw_data
-                [cursor..cursor+TRACK_SECLEN_SIZE as usize]
-                .try_into()?,
-        ); // 音轨大小
-        cursor += TRACK_SECLEN_SIZE as usize;
-        let mut message_num = 0;
+        let chunk_header = ChunkHeader::parse(raw_data)?;
+        chunk_header.expect(b"MTrk")?; // 音轨标识 值为"MTrk"
+        let m_magic = chunk_header.m_magic;
+        let m_track_size = chunk_header.m_size; // 音轨大小
+        cursor += CHUNK_HEADER_SIZE;
         let mut midi_message = Vec::new();
         let mut pre_status = Option::None; // 保存前一个状态
+        let track_end = cursor + m_track_size as usize;
+        if options.strict && raw_data.len() < track_end {
+            return Err(MidiParseError::eof(raw_data.len()));
+        }
+        // 严格模式下事件只在音轨的范围内解析 越界当作数据提前结束
+        // 宽松模式以End of Track为准 声明的长度只用来判断下一个chunk从哪开始
+        let track_data = if options.strict {
+            &raw_data[..track_end]
+        } else {
+            raw_data
+        };
+        let mut end_of_track = false;
+        let mut truncated = None;
+        let mut next_chunk = false; // 越过声明的长度之后遇到了下一个chunk
+        let mut declared_end = (0, cursor); // 声明的长度以内的(事件数, 结束位置)
         loop {
-            let _midi_message = MidiMessage::parse(&raw_data[cursor..], &pre_status)?;
-            cursor += _midi_message.get_message_size() as usize;
-            message_num += 1;
-            pre_status = Some(_midi_message.m_status);
+            if !options.strict && cursor >= track_data.len() {
+                break;
+            }
+            if !options.strict && cursor >= track_end && is_chunk_start(&track_data[cursor..]) {
+                next_chunk = true;
+                break;
+            }
+            let _midi_message = match MidiMessage::parse(&track_data[cursor..], &pre_status) {
+                Ok(midi_message) => midi_message,
+                Err(err) if !options.strict => {
+                    truncated = Some(err.at(cursor));
+                    break;
+                }
+                Err(err) => return Err(err.at(cursor)),
+            };
+            cursor += _midi_message.get_message_size();
+            if _midi_message.is_channel_message() {
+                pre_status = Some(_midi_message.m_status);
+            }
+            end_of_track = matches!(
+                _midi_message.m_ment_event,
+                Event::Meta {
+                    meta: MetaEvent::EndOfTrack
+                }
+            );
             midi_message.push(_midi_message);
-            println!("cursor: {cursor}, message_num: {message_num}");
-            if cursor >= m_track_size as usize {
+            if cursor <= track_end {
+                declared_end = (midi_message.len(), cursor);
+            }
+            if end_of_track || (options.strict && cursor >= track_end) {
                 break;
             }
         }
 
+        // 没有End of Track 越过声明的长度之后也没遇到下一个chunk
+        // 说明后面是垃圾数据 丢掉声明的长度之外解析出来的事件
+        let discard = !options.strict
+            && !end_of_track
+            && !next_chunk
+            && cursor > track_end
+            && track_end <= raw_data.len();
+        if discard {
+            midi_message.truncate(declared_end.0);
+            cursor = declared_end.1;
+            truncated = None;
+        }
+        let truncated = match truncated {
+            Some(error) => {
+                warnings.push(ParseWarning::TrackTruncated { error });
+                true
+            }
+            None => false,
+        };
+
+        let chunk_length = if options.strict || cursor == track_end || discard {
+            track_end
+        } else if end_of_track {
+            // End of Track之后声明的位置上正好是下一个chunk 说明只是补了空白
+            if track_end <= raw_data.len() && is_chunk_start(&raw_data[track_end..]) {
+                track_end
+            } else {
+                warnings.push(ParseWarning::TrackLengthMismatch {
+                    offset: 0,
+                    track: None,
+                    declared: m_track_size,
+                    actual: (cursor - CHUNK_HEADER_SIZE) as u32,
+                });
+                cursor
+            }
+        } else if truncated && cursor < track_end && track_end <= raw_data.len() {
+            track_end
+        } else if truncated {
+            raw_data.len()
+        } else {
+            cursor
+        };
+        if !options.strict && !end_of_track {
+            warnings.push(ParseWarning::MissingEndOfTrack {
+                offset: cursor,
+                track: None,
+            });
+        }
+
         midi_track.m_magic = m_magic;
         midi_track.m_track_size = m_track_size;
         midi_track.m_midi_message = midi_message;
-        Ok(midi_track)
+        Ok((midi_track, chunk_length))
+    }
+
+    // 音轨大小按实际写出的字节重新计算
+    pub fn get_raw_with(&self, running_status: RunningStatus) -> Vec<u8> {
+        let mut events: Vec<u8> = Vec::new();
+        let mut pre_status = None;
+        for midi_message in self.m_midi_message.iter() {
+            midi_message.write_raw(&mut events, &mut pre_status, running_status);
+        }
+        let mut raw_data: Vec<u8> = Vec::with_capacity(events.len() + 8);
+        raw_data.extend_from_slice(b"MTrk");
+        raw_data.extend_from_slice(&(events.len() as u32).to_be_bytes());
+        raw_data.extend_from_slice(&events);
+        raw_data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 超出变长int范围的间隔拆成几段 后面事件的tick不变
+    #[test]
+    fn from_absolute_long_gap() {
+        let far = MIDI_INT_MAX as u64 * 20 + 5;
+        let midi_track = MidiTrack::from_absolute([
+            (
+                0,
+                MidiMessage::from_meta(
+                    0,
+                    MetaEvent::Text {
+                        text: b"a".to_vec(),
+                    },
+                ),
+            ),
+            (
+                far,
+                MidiMessage::from_meta(
+                    0,
+                    MetaEvent::Text {
+                        text: b"b".to_vec(),
+                    },
+                ),
+            ),
+        ]);
+        let texts: Vec<(u64, Option<String>)> = midi_track
+            .events()
+            .filter_map(|(tick, event)| match event {
+                Event::Meta {
+                    meta: meta @ MetaEvent::Text { .. },
+                } => Some((tick, meta.text())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            [(0, Some("a".to_string())), (far, Some("b".to_string()))]
+        );
+        let raw_data = midi_track.get_raw();
+        let (parsed, _) =
+            MidiTrack::parse_with_options(&raw_data, &ParseOptions::default(), &mut Vec::new())
+                .unwrap();
+        assert_eq!(parsed.events().last().unwrap().0, far);
+    }
+
+    const NOTE_ON: [u8; 4] = [0x00, 0x90, 0x3C, 0x40];
+    const NOTE_OFF: [u8; 4] = [0x00, 0x80, 0x3C, 0x00];
+    const END_OF_TRACK: [u8; 4] = [0x00, 0xFF, 0x2F, 0x00];
+
+    // 声明的长度和数据分开给 用来构造长度不对的chunk
+    fn chunk(magic: &[u8; 4], size: usize, data: &[&[u8]]) -> Vec<u8> {
+        let mut raw_data = magic.to_vec();
+        raw_data.extend_from_slice(&(size as u32).to_be_bytes());
+        raw_data.extend(data.concat());
+        raw_data
+    }
+
+    fn track(data: &[&[u8]]) -> Vec<u8> {
+        chunk(b"MTrk", data.concat().len(), data)
+    }
+
+    fn parse_lenient(raw_data: &[u8]) -> (Vec<usize>, Vec<UnknownChunk>, Vec<ParseWarning>) {
+        let mut warnings = Vec::new();
+        let (tracks, unknown_chunks) =
+            Tracks::parse_with_options(raw_data, &ParseOptions::lenient(), &mut warnings).unwrap();
+        let event_counts = tracks
+            .0
+            .iter()
+            .map(|track| track.m_midi_message.len())
+            .collect();
+        (event_counts, unknown_chunks, warnings)
+    }
+
+    // 声明的长度比实际短 以End of Track为准 下一个音轨照常读取
+    #[test]
+    fn lenient_short_chunk_length() {
+        let mut raw_data = chunk(b"MTrk", 4, &[&NOTE_ON, &NOTE_OFF, &END_OF_TRACK]);
+        raw_data.extend(track(&[&END_OF_TRACK]));
+        let (event_counts, _, warnings) = parse_lenient(&raw_data);
+        assert_eq!(event_counts, [3, 1]);
+        assert_eq!(
+            warnings,
+            [ParseWarning::TrackLengthMismatch {
+                offset: 0,
+                track: Some(0),
+                declared: 4,
+                actual: 12,
+            }]
+        );
+    }
+
+    // 声明的长度比实际长 下一个chunk紧跟在End of Track之后
+    #[test]
+    fn lenient_long_chunk_length() {
+        let mut raw_data = chunk(b"MTrk", 100, &[&NOTE_ON, &END_OF_TRACK]);
+        raw_data.extend(track(&[&END_OF_TRACK]));
+        let (event_counts, _, warnings) = parse_lenient(&raw_data);
+        assert_eq!(event_counts, [2, 1]);
+        assert_eq!(
+            warnings,
+            [ParseWarning::TrackLengthMismatch {
+                offset: 0,
+                track: Some(0),
+                declared: 100,
+                actual: 8,
+            }]
+        );
+    }
+
+    // End of Track之后补了空白 声明的位置上正好是下一个chunk 不算错误
+    #[test]
+    fn lenient_padding_after_end_of_track() {
+        let mut raw_data = chunk(b"MTrk", 12, &[&NOTE_ON, &END_OF_TRACK, &[0; 4]]);
+        raw_data.extend(track(&[&END_OF_TRACK]));
+        let (event_counts, _, warnings) = parse_lenient(&raw_data);
+        assert_eq!(event_counts, [2, 1]);
+        assert_eq!(warnings, []);
+    }
+
+    #[test]
+    fn lenient_missing_end_of_track() {
+        let mut raw_data = track(&[&NOTE_ON, &NOTE_OFF]);
+        raw_data.extend(track(&[&END_OF_TRACK]));
+        let (event_counts, _, warnings) = parse_lenient(&raw_data);
+        assert_eq!(event_counts, [2, 1]);
+        assert_eq!(
+            warnings,
+            [ParseWarning::MissingEndOfTrack {
+                offset: 16,
+                track: Some(0),
+            }]
+        );
+    }
+
+    // 最后一个音轨没有End of Track 后面的垃圾数据不能当成事件
+    #[test]
+    fn lenient_missing_end_of_track_before_garbage() {
+        let mut raw_data = track(&[&NOTE_ON]);
+        raw_data.extend([0x12, 0x34, 0x56]);
+        let (event_counts, _, warnings) = parse_lenient(&raw_data);
+        assert_eq!(event_counts, [1]);
+        assert_eq!(
+            warnings,
+            [
+                ParseWarning::MissingEndOfTrack {
+                    offset: 12,
+                    track: Some(0),
+                },
+                ParseWarning::TrailingData {
+                    offset: 12,
+                    size: 3,
+                },
+            ]
+        );
+    }
+
+    // 文件在事件中间结束 保留之前的事件
+    #[test]
+    fn lenient_truncated() {
+        let raw_data = chunk(b"MTrk", 12, &[&NOTE_ON, &[0x00, 0x90]]);
+        let (event_counts, _, warnings) = parse_lenient(&raw_data);
+        assert_eq!(event_counts, [1]);
+        assert_eq!(
+            warnings,
+            [
+                ParseWarning::TrackTruncated {
+                    error: MidiParseError::UnexpectedEof {
+                        offset: 14,
+                        track: Some(0),
+                    },
+                },
+                ParseWarning::MissingEndOfTrack {
+                    offset: 12,
+                    track: Some(0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn lenient_trailing_data() {
+        let mut raw_data = track(&[&END_OF_TRACK]);
+        raw_data.extend([0x00, 0x01, 0x02]);
+        let (event_counts, _, warnings) = parse_lenient(&raw_data);
+        assert_eq!(event_counts, [1]);
+        assert_eq!(
+            warnings,
+            [ParseWarning::TrailingData {
+                offset: 12,
+                size: 3,
+            }]
+        );
+    }
+
+    // 不认识的chunk原样保存 记住它在第几个音轨之前
+    #[test]
+    fn lenient_unknown_chunk() {
+        let mut raw_data = track(&[&END_OF_TRACK]);
+        raw_data.extend(chunk(b"XFIH", 3, &[&[1, 2, 3]]));
+        raw_data.extend(track(&[&END_OF_TRACK]));
+        let (event_counts, unknown_chunks, warnings) = parse_lenient(&raw_data);
+        assert_eq!(event_counts, [1, 1]);
+        assert_eq!(warnings, []);
+        assert_eq!(unknown_chunks.len(), 1);
+        assert_eq!(unknown_chunks[0].m_magic, *b"XFIH");
+        assert_eq!(unknown_chunks[0].m_data, [1, 2, 3]);
+        assert_eq!(unknown_chunks[0].m_track_index, 1);
     }
 }
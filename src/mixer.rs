@@ -0,0 +1,77 @@
+use std::collections::HashSet;
+
+use crate::midi_format::base::MidiChannel;
+
+// 静音或者独奏的对象
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MixTarget {
+    Track { index: usize },
+    Channel { channel: MidiChannel },
+}
+
+impl std::fmt::Display for MixTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MixTarget::Track { index } => write!(f, "音轨{index}"),
+            MixTarget::Channel { channel } => write!(f, "通道{channel}"),
+        }
+    }
+}
+
+// 播放时每个音轨和通道的静音/独奏状态
+// 音轨和通道分开判断 两边都能听到的音符才会发出声音
+// 有独奏的对象时 同一类里没有独奏的都不发声
+#[derive(Debug, Clone, Default)]
+pub struct MixState {
+    m_muted: HashSet<MixTarget>,
+    m_solo: HashSet<MixTarget>,
+}
+
+impl MixState {
+    pub fn set_muted(&mut self, target: MixTarget, muted: bool) {
+        set_contains(&mut self.m_muted, target, muted);
+    }
+
+    pub fn set_solo(&mut self, target: MixTarget, solo: bool) {
+        set_contains(&mut self.m_solo, target, solo);
+    }
+
+    // 返回切换后是否静音
+    pub fn toggle_muted(&mut self, target: MixTarget) -> bool {
+        let muted = !self.m_muted.contains(&target);
+        self.set_muted(target, muted);
+        muted
+    }
+
+    // 返回切换后是否独奏
+    pub fn toggle_solo(&mut self, target: MixTarget) -> bool {
+        let solo = !self.m_solo.contains(&target);
+        self.set_solo(target, solo);
+        solo
+    }
+
+    // track音轨里channel通道上的音符能不能听到 meta和sysex事件没有通道
+    pub fn is_audible(&self, track: usize, channel: Option<MidiChannel>) -> bool {
+        let track = MixTarget::Track { index: track };
+        let channel = channel.map(|channel| MixTarget::Channel { channel });
+        self.passes(track, |target| matches!(target, MixTarget::Track { .. }))
+            && channel.is_none_or(|channel| {
+                self.passes(channel, |target| {
+                    matches!(target, MixTarget::Channel { .. })
+                })
+            })
+    }
+
+    fn passes(&self, target: MixTarget, same_kind: impl Fn(&MixTarget) -> bool) -> bool {
+        !self.m_muted.contains(&target)
+            && (self.m_solo.contains(&target) || !self.m_solo.iter().any(same_kind))
+    }
+}
+
+fn set_contains(targets: &mut HashSet<MixTarget>, target: MixTarget, contains: bool) {
+    if contains {
+        targets.insert(target);
+    } else {
+        targets.remove(&target);
+    }
+}
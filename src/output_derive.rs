@@ -1,13 +1,37 @@
 use std::error::Error;
 
-use cpal::traits::HostTrait;
+use cpal::traits::{DeviceTrait, HostTrait};
 use cpal::Device;
 
+use crate::config::CONFIG;
+
 pub fn init_output_derive() -> Result<Device, Box<dyn Error>> {
     let host = cpal::default_host();
 
-    let device = host
-        .default_output_device()
-        .expect("Failed to find a default output device");
+    if let Some(name) = &CONFIG.output_device {
+        let device = host
+            .output_devices()?
+            .find(|device| device.name().is_ok_and(|device_name| device_name == *name))
+            .ok_or(format!("未找到输出设备: {name}"))?;
+        return Ok(device);
+    }
+    let device = host.default_output_device().ok_or("未找到默认的输出设备")?;
     Ok(device)
 }
+
+pub fn list_output_devices() -> Result<(), Box<dyn Error>> {
+    let host = cpal::default_host();
+    let default_name = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    for device in host.output_devices()? {
+        let name = device.name()?;
+        let mark = if Some(&name) == default_name.as_ref() {
+            " (默认)"
+        } else {
+            ""
+        };
+        println!("{name}{mark}");
+    }
+    Ok(())
+}
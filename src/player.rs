@@ -0,0 +1,666 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    error::Error,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    midi_format::{
+        bar_map::BarMap,
+        base::MidiChannel,
+        midi_message::{Event, MessageEvent, MidiMessage, SysExAssembler},
+        sequencer::{merge_tracks, SequencedEvent},
+        tempo_map::TempoMap,
+        MidiFile,
+    },
+    mixer::{MixState, MixTarget},
+    scheduler::SharedScheduler,
+    synthesizers::{is_master_volume, is_system_reset, reset_messages, SharedSynthesizer},
+};
+
+pub const MIN_SPEED: f64 = 0.5;
+pub const MAX_SPEED: f64 = 1.5;
+const RAMP_TARGET: f64 = 1.0; // 循环加速最多加到原速
+const LOOKAHEAD: Duration = Duration::from_millis(100); // 提前多久把事件排进队列
+const POLL_INTERVAL: Duration = Duration::from_millis(10); // 检查播放进度的间隔
+
+pub struct PlayOptions {
+    pub speed: f64,                    // 播放速度 1.0是原速 不改变音高
+    pub loop_bars: Option<(u32, u32)>, // 循环播放第N到第M小节(包括M)
+    pub ramp_step: f64,                // 每完整循环一次速度增加多少 0表示不加速
+    pub muted: Vec<MixTarget>,
+    pub solo: Vec<MixTarget>,
+}
+
+impl Default for PlayOptions {
+    fn default() -> Self {
+        PlayOptions {
+            speed: 1.0,
+            loop_bars: None,
+            ramp_step: 0.0,
+            muted: Vec::new(),
+            solo: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerState {
+    Playing,
+    Paused,
+    Stopped, // 停止后回到开头 等待重新播放
+}
+
+// 从其他线程发给播放器的命令
+#[derive(Debug, Clone, Copy)]
+pub enum PlayerCommand {
+    Play,
+    Pause,
+    Stop,
+    SeekTick { tick: u64 },
+    SeekTime { time: Duration },
+    SeekBar { bar: u32, beat: u32 },
+    SetSpeed { speed: f64 },
+    SetLoop { start_bar: u32, end_bar: u32 },
+    ClearLoop,
+    Quit,
+}
+
+// 可以复制到其他线程里控制播放器 播放器结束后发送的命令直接丢弃
+// 静音和独奏直接修改共享的状态 从下一个音符开始生效 已经在响的音符正常结束
+#[derive(Debug, Clone)]
+pub struct PlayerHandle {
+    m_sender: Sender<PlayerCommand>,
+    m_mix: Arc<Mutex<MixState>>,
+    m_bar_count: u32,
+}
+
+impl PlayerHandle {
+    pub fn send(&self, command: PlayerCommand) {
+        let _ = self.m_sender.send(command);
+    }
+
+    pub fn play(&self) {
+        self.send(PlayerCommand::Play);
+    }
+
+    pub fn pause(&self) {
+        self.send(PlayerCommand::Pause);
+    }
+
+    pub fn stop(&self) {
+        self.send(PlayerCommand::Stop);
+    }
+
+    pub fn seek_tick(&self, tick: u64) {
+        self.send(PlayerCommand::SeekTick { tick });
+    }
+
+    pub fn seek_time(&self, time: Duration) {
+        self.send(PlayerCommand::SeekTime { time });
+    }
+
+    pub fn seek_bar(&self, bar: u32, beat: u32) {
+        self.send(PlayerCommand::SeekBar { bar, beat });
+    }
+
+    pub fn set_speed(&self, speed: f64) {
+        self.send(PlayerCommand::SetSpeed { speed });
+    }
+
+    pub fn set_loop(&self, start_bar: u32, end_bar: u32) -> Result<(), Box<dyn Error>> {
+        check_loop(start_bar, end_bar, self.m_bar_count)?;
+        self.send(PlayerCommand::SetLoop { start_bar, end_bar });
+        Ok(())
+    }
+
+    pub fn clear_loop(&self) {
+        self.send(PlayerCommand::ClearLoop);
+    }
+
+    pub fn quit(&self) {
+        self.send(PlayerCommand::Quit);
+    }
+
+    pub fn set_muted(&self, target: MixTarget, muted: bool) {
+        self.m_mix.lock().unwrap().set_muted(target, muted);
+    }
+
+    pub fn set_solo(&self, target: MixTarget, solo: bool) {
+        self.m_mix.lock().unwrap().set_solo(target, solo);
+    }
+
+    pub fn toggle_muted(&self, target: MixTarget) -> bool {
+        self.m_mix.lock().unwrap().toggle_muted(target)
+    }
+
+    pub fn toggle_solo(&self, target: MixTarget) -> bool {
+        self.m_mix.lock().unwrap().toggle_solo(target)
+    }
+}
+
+// 把MIDI文件的事件按时间交给调度器 由音频回调在准确的采样上发给合成器
+// run()所在的线程提前LOOKAHEAD把事件排进队列 同时接收命令 暂停和跳转时撤回还没有渲染的事件
+// 播放位置由调度器的采样时钟决定 不依赖线程醒来的时间
+// 改变速度只是按比例缩放事件之间的时间 音高不变
+// 设置循环后 播放到循环结束的小节时跳回循环开始的小节
+// 下一遍和前面的事件一样提前排进队列 在上一遍结束的那一帧开始
+// 一次循环中间没有暂停或者跳转才算完整地练习了一遍 这时按m_ramp_step加速
+// 和实时演奏共用合成器时 停止 跳转和复位都只影响文件用到的通道
+// 实时演奏用live_channel选出的通道 不会被文件的消息影响
+pub struct Player<'a> {
+    m_events: Vec<SequencedEvent<'a>>,
+    m_tempo_map: TempoMap,
+    m_bar_map: BarMap,
+    m_bar_count: u32,
+    m_channels: Vec<MidiChannel>, // 文件用到的通道
+    m_master_volume: bool,        // 文件里改变了主音量 复位时才恢复主音量
+    m_scheduler: SharedScheduler,
+    m_sample_rate: u64,
+    m_sysex_assembler: SysExAssembler,
+    m_mix: Arc<Mutex<MixState>>,
+    m_sender: Sender<PlayerCommand>,
+    m_receiver: Receiver<PlayerCommand>,
+    m_state: PlayerState,
+    m_cursor: usize,                   // 下一个要排进队列的事件
+    m_pending: VecDeque<(u64, usize)>, // 已经排进队列的(帧, 事件序号) 渲染后通知on_event
+    m_position: u64,                   // m_anchor_frame时的播放位置(微秒)
+    m_anchor_frame: u64,               // 开始播放 跳转或者改变速度时的采样时钟
+    m_speed: f64,
+    m_loop: Option<(u64, u64)>, // 循环的开始和结束tick
+    m_loop_clean: bool,         // 这一次循环中间没有暂停或者跳转
+    m_ramp_step: f64,
+    m_last_pass: Option<LoopPass>, // 已经排好下一遍循环 还没播到时上一遍的进度
+    m_muted_notes: HashSet<(usize, MidiChannel, u8)>, // 因为静音没有发出的音符(音轨, 通道, 键)
+}
+
+// 一遍循环的播放进度 下一遍开始之前撤回队列时用来恢复
+#[derive(Debug, Clone, Copy)]
+struct LoopPass {
+    m_position: u64,
+    m_anchor_frame: u64,
+    m_speed: f64,
+    m_cursor: usize, // 这一遍最后一个事件之后
+}
+
+impl<'a> Player<'a> {
+    pub fn new(
+        midi_file: &'a MidiFile,
+        synthesizer: SharedSynthesizer,
+        scheduler: SharedScheduler,
+        options: &PlayOptions,
+    ) -> Result<Player<'a>, Box<dyn Error>> {
+        let (sender, receiver) = channel();
+        let mut mix = MixState::default();
+        for target in options.muted.iter() {
+            mix.set_muted(*target, true);
+        }
+        for target in options.solo.iter() {
+            mix.set_solo(*target, true);
+        }
+        let sample_rate = synthesizer.lock().unwrap().get_sample_rate() as u64;
+        let events = merge_tracks(midi_file);
+        let bar_map = BarMap::new(midi_file);
+        // 最后一个事件正好在小节线上时 它只是结束的标记 不算新的一小节
+        let end_tick = events.last().map_or(0, |event| event.m_tick);
+        let bar_count = bar_map.tick_to_bar(end_tick.saturating_sub(1)).0;
+        let mut sysex_assembler = SysExAssembler::default();
+        let master_volume = events
+            .iter()
+            .any(|event| match &event.m_message.m_ment_event {
+                Event::SysEx { sysex } => sysex_assembler
+                    .push(sysex)
+                    .is_some_and(|data| is_master_volume(&data)),
+                _ => false,
+            });
+        let mut player = Player {
+            m_events: events,
+            m_tempo_map: TempoMap::new(midi_file),
+            m_bar_map: bar_map,
+            m_bar_count: bar_count,
+            m_channels: midi_file.channels(),
+            m_master_volume: master_volume,
+            m_scheduler: scheduler,
+            m_sample_rate: sample_rate,
+            m_sysex_assembler: SysExAssembler::default(),
+            m_mix: Arc::new(Mutex::new(mix)),
+            m_sender: sender,
+            m_receiver: receiver,
+            m_state: PlayerState::Stopped,
+            m_cursor: 0,
+            m_pending: VecDeque::new(),
+            m_position: 0,
+            m_anchor_frame: 0,
+            m_speed: 1.0,
+            m_loop: None,
+            m_loop_clean: true,
+            m_ramp_step: options.ramp_step.max(0.0),
+            m_last_pass: None,
+            m_muted_notes: HashSet::new(),
+        };
+        player.apply(PlayerCommand::SetSpeed {
+            speed: options.speed,
+        });
+        if let Some((start_bar, end_bar)) = options.loop_bars {
+            check_loop(start_bar, end_bar, bar_count)?;
+            player.apply(PlayerCommand::SetLoop { start_bar, end_bar });
+        }
+        Ok(player)
+    }
+
+    pub fn handle(&self) -> PlayerHandle {
+        PlayerHandle {
+            m_sender: self.m_sender.clone(),
+            m_mix: self.m_mix.clone(),
+            m_bar_count: self.m_bar_count,
+        }
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.m_state
+    }
+
+    // 当前的播放位置(微秒)
+    pub fn position(&self) -> u64 {
+        match self.m_state {
+            PlayerState::Playing => self.frame_to_position(self.clock()),
+            PlayerState::Paused | PlayerState::Stopped => self.m_position,
+        }
+    }
+
+    pub fn position_tick(&self) -> u64 {
+        self.m_tempo_map.micros_to_ticks(self.position())
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.m_speed
+    }
+
+    pub fn bar_map(&self) -> &BarMap {
+        &self.m_bar_map
+    }
+
+    // 曲子一共有多少小节
+    pub fn bar_count(&self) -> u32 {
+        self.m_bar_count
+    }
+
+    // 从当前位置开始播放 直到曲子结束或者收到Quit
+    // 事件渲染出来以后调用on_event 参数是事件的绝对tick和消息
+    pub fn run(&mut self, mut on_event: impl FnMut(u64, &MidiMessage)) {
+        self.apply(PlayerCommand::Play);
+        loop {
+            let command = match self.m_state {
+                PlayerState::Playing => {
+                    let clock = self.clock();
+                    while let Some((_, index)) =
+                        self.m_pending.pop_front_if(|(frame, _)| *frame < clock)
+                    {
+                        let event = self.m_events[index];
+                        on_event(event.m_tick, event.m_message);
+                    }
+                    let lookahead = LOOKAHEAD.as_micros() as u64 * self.m_sample_rate / 1_000_000;
+                    // 所有事件都已经渲染 曲子结束
+                    if self.schedule_until(clock + lookahead) && self.m_pending.is_empty() {
+                        return;
+                    }
+                    match self.m_receiver.recv_timeout(POLL_INTERVAL) {
+                        Ok(command) => command,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                PlayerState::Paused | PlayerState::Stopped => match self.m_receiver.recv() {
+                    Ok(command) => command,
+                    Err(_) => return,
+                },
+            };
+            if !self.apply(command) {
+                return;
+            }
+        }
+    }
+
+    // 返回false时结束播放
+    fn apply(&mut self, command: PlayerCommand) -> bool {
+        match command {
+            PlayerCommand::Play => {
+                if self.m_state != PlayerState::Playing {
+                    self.m_anchor_frame = self.clock();
+                    self.m_state = PlayerState::Playing;
+                }
+            }
+            PlayerCommand::Pause => {
+                if self.m_state == PlayerState::Playing {
+                    self.m_loop_clean = false;
+                    let clock = self.unschedule();
+                    self.m_position = self.frame_to_position(clock);
+                    self.m_state = PlayerState::Paused;
+                    self.release_notes();
+                }
+            }
+            PlayerCommand::Stop => {
+                self.seek(0);
+                self.m_state = PlayerState::Stopped;
+            }
+            PlayerCommand::SeekTick { tick } => self.seek(tick),
+            PlayerCommand::SeekTime { time } => {
+                let micros = time.as_micros().min(u64::MAX as u128) as u64;
+                let tick = self.m_tempo_map.micros_to_ticks(micros);
+                self.seek(tick)
+            }
+            PlayerCommand::SeekBar { bar, beat } => {
+                self.seek(self.m_bar_map.bar_to_tick(bar, beat))
+            }
+            // 已经排进队列的事件按新的速度重新排
+            PlayerCommand::SetSpeed { speed } => {
+                if self.m_state == PlayerState::Playing {
+                    let clock = self.unschedule();
+                    self.m_position = self.frame_to_position(clock);
+                    self.m_anchor_frame = clock;
+                }
+                self.m_speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+            }
+            // 设置后马上跳到循环开始的小节 超出曲子的循环不理会
+            PlayerCommand::SetLoop { start_bar, end_bar } => {
+                if check_loop(start_bar, end_bar, self.m_bar_count).is_err() {
+                    return true;
+                }
+                let start_tick = self.m_bar_map.bar_to_tick(start_bar.min(end_bar), 1);
+                let end_tick = self
+                    .m_bar_map
+                    .bar_to_tick(start_bar.max(end_bar).saturating_add(1), 1);
+                self.m_loop = Some((start_tick, end_tick));
+                self.seek(start_tick);
+                self.m_loop_clean = true;
+            }
+            // 已经排进队列的下一遍撤回 从当前位置继续往后播
+            PlayerCommand::ClearLoop => {
+                if self.m_state == PlayerState::Playing {
+                    self.unschedule();
+                }
+                self.m_loop = None;
+            }
+            PlayerCommand::Quit => {
+                self.unschedule();
+                self.release_notes();
+                return false;
+            }
+        }
+        true
+    }
+
+    fn clock(&self) -> u64 {
+        self.m_scheduler.lock().unwrap().clock()
+    }
+
+    // 下一遍循环开始之前的帧按上一遍的进度计算
+    fn frame_to_position(&self, frame: u64) -> u64 {
+        let (position, anchor_frame, speed) = match self.m_last_pass {
+            Some(last_pass) if frame < self.m_anchor_frame => (
+                last_pass.m_position,
+                last_pass.m_anchor_frame,
+                last_pass.m_speed,
+            ),
+            _ => (self.m_position, self.m_anchor_frame, self.m_speed),
+        };
+        let frames = frame.saturating_sub(anchor_frame) as f64;
+        position + (frames * 1_000_000f64 / self.m_sample_rate as f64 * speed) as u64
+    }
+
+    fn tick_to_frame(&self, tick: u64) -> u64 {
+        let micros = self
+            .m_tempo_map
+            .ticks_to_micros(tick)
+            .saturating_sub(self.m_position) as f64;
+        self.m_anchor_frame.saturating_add(
+            (micros / self.m_speed * self.m_sample_rate as f64 / 1_000_000f64) as u64,
+        )
+    }
+
+    // 把horizon帧以前的事件排进调度器 所有事件都排完并且没有循环时返回true
+    // 到了循环结束的位置时 把下一遍也排进去
+    fn schedule_until(&mut self, horizon: u64) -> bool {
+        let scheduler = self.m_scheduler.clone();
+        let mut scheduler = scheduler.lock().unwrap();
+        loop {
+            let event = self.m_events.get(self.m_cursor).copied();
+            match (event, self.m_loop) {
+                (Some(event), loop_ticks)
+                    if loop_ticks.is_none_or(|(_, end_tick)| event.m_tick < end_tick) =>
+                {
+                    let frame = self.tick_to_frame(event.m_tick);
+                    if frame > horizon {
+                        return false;
+                    }
+                    if self.is_audible(&event) {
+                        match self.replace_reset(event.m_message) {
+                            Some(messages) => {
+                                for midi_message in messages {
+                                    scheduler.schedule(frame, midi_message);
+                                }
+                            }
+                            None => scheduler.schedule(frame, event.m_message.clone()),
+                        }
+                    }
+                    self.m_pending.push_back((frame, self.m_cursor));
+                    self.m_cursor += 1;
+                }
+                (_, None) => return true,
+                (_, Some((_, end_tick))) => {
+                    let end_frame = self.tick_to_frame(end_tick);
+                    // 一遍连一帧都不到时不再循环 避免一直排下去
+                    if end_frame > horizon || end_frame <= self.m_anchor_frame {
+                        return false;
+                    }
+                    for midi_message in self.restart_loop(end_frame) {
+                        scheduler.schedule(end_frame, midi_message);
+                    }
+                }
+            }
+        }
+    }
+
+    // 撤回还没到时间的事件 之后从第一个没有播放的事件重新排 返回当前的采样时钟
+    // 已经排好的下一遍还没开始时 回到上一遍的进度
+    fn unschedule(&mut self) -> u64 {
+        let clock = {
+            let mut scheduler = self.m_scheduler.lock().unwrap();
+            scheduler.clear();
+            scheduler.clock()
+        };
+        self.m_sysex_assembler = SysExAssembler::default();
+        let resume = self
+            .m_pending
+            .iter()
+            .find(|(frame, _)| *frame > clock)
+            .copied();
+        let last_pass = self.m_last_pass.take();
+        match last_pass {
+            Some(last_pass) if clock < self.m_anchor_frame => {
+                let next_pass_frame = self.m_anchor_frame;
+                self.m_position = last_pass.m_position;
+                self.m_anchor_frame = last_pass.m_anchor_frame;
+                self.m_speed = last_pass.m_speed;
+                self.m_cursor = match resume {
+                    Some((frame, index)) if frame < next_pass_frame => index,
+                    _ => last_pass.m_cursor,
+                };
+            }
+            _ => {
+                if let Some((_, index)) = resume {
+                    self.m_cursor = index;
+                }
+            }
+        }
+        // 到时间的事件还留在队列里 渲染后照常通知
+        self.m_pending.retain(|(frame, _)| *frame <= clock);
+        clock
+    }
+
+    // 静音只拦截NoteOn和它对应的NoteOff 控制器等消息照常发送 取消静音后音色和音量都是对的
+    fn is_audible(&mut self, event: &SequencedEvent) -> bool {
+        let (Event::Midi { message }, Some(channel)) =
+            (&event.m_message.m_ment_event, event.m_message.channel())
+        else {
+            return true;
+        };
+        match *message {
+            MessageEvent::NoteOn { key, .. } if message.is_note_on() => {
+                let audible = self
+                    .m_mix
+                    .lock()
+                    .unwrap()
+                    .is_audible(event.m_track, Some(channel));
+                if !audible {
+                    self.m_muted_notes
+                        .insert((event.m_track, channel, key.bits()));
+                }
+                audible
+            }
+            MessageEvent::NoteOn { key, .. } | MessageEvent::NoteOff { key, .. } => !self
+                .m_muted_notes
+                .remove(&(event.m_track, channel, key.bits())),
+            _ => true,
+        }
+    }
+
+    // 在上一遍结束的end_frame跳回循环开始的位置 上一次循环完整播放时加速
+    // 返回在end_frame上复位通道和重放状态的消息
+    // 新的一遍从上一遍结束的那一帧算起 循环多少遍都不会累积误差
+    fn restart_loop(&mut self, end_frame: u64) -> Vec<MidiMessage> {
+        let Some((start_tick, _)) = self.m_loop else {
+            return Vec::new();
+        };
+        let last_pass = LoopPass {
+            m_position: self.m_position,
+            m_anchor_frame: self.m_anchor_frame,
+            m_speed: self.m_speed,
+            m_cursor: self.m_cursor,
+        };
+        if self.m_loop_clean && self.m_speed < RAMP_TARGET {
+            self.m_speed = (self.m_speed + self.m_ramp_step).min(RAMP_TARGET);
+        }
+        let messages = self.chase(start_tick);
+        self.m_anchor_frame = end_frame;
+        self.m_loop_clean = true;
+        self.m_last_pass = Some(last_pass);
+        messages
+    }
+
+    // 跳到tick 先撤回队列里的事件 再把复位和重放状态的消息排在当前的采样时钟上
+    // 由音频回调在下一块开始时处理
+    fn seek(&mut self, tick: u64) {
+        self.m_loop_clean = false;
+        let clock = self.unschedule();
+        let messages = self.chase(tick);
+        let mut scheduler = self.m_scheduler.lock().unwrap();
+        for midi_message in messages {
+            scheduler.schedule(clock, midi_message);
+        }
+        self.m_anchor_frame = clock;
+    }
+
+    // 把播放位置移到tick 返回切断文件通道的声音并复位这些通道
+    // 然后按顺序重放tick之前除了音符以外的通道消息和sysex的消息
+    // 这样每个通道的音色 控制器(包括RPN) 弯音都和从头播放到这里时一样
+    fn chase(&mut self, tick: u64) -> Vec<MidiMessage> {
+        // 超出曲子长度时停在最后
+        let tick = tick.min(self.m_events.last().map_or(0, |event| event.m_tick));
+        self.m_cursor = self.m_events.partition_point(|event| event.m_tick < tick);
+        self.m_position = self.m_tempo_map.ticks_to_micros(tick);
+        self.m_sysex_assembler = SysExAssembler::default();
+        // 复位会切断所有声音 之前被拦截的音符不用再管
+        self.m_muted_notes.clear();
+        let mut messages = reset_messages(&self.m_channels, self.m_master_volume);
+        for index in 0..self.m_cursor {
+            let event = self.m_events[index];
+            match &event.m_message.m_ment_event {
+                Event::Midi {
+                    message:
+                        MessageEvent::NoteOn { .. }
+                        | MessageEvent::NoteOff { .. }
+                        | MessageEvent::Aftertouch { .. },
+                }
+                | Event::Meta { .. }
+                | Event::None => {}
+                _ => match self.replace_reset(event.m_message) {
+                    Some(reset) => messages.extend(reset),
+                    None => messages.push(event.m_message.clone()),
+                },
+            }
+        }
+        messages
+    }
+
+    // 文件里的系统复位sysex会连实时演奏的通道一起复位 换成只复位文件用到的通道
+    // 不是系统复位时返回None 原样发送
+    fn replace_reset(&mut self, midi_message: &MidiMessage) -> Option<Vec<MidiMessage>> {
+        let Event::SysEx { sysex } = &midi_message.m_ment_event else {
+            return None;
+        };
+        let data = self.m_sysex_assembler.push(sysex)?;
+        is_system_reset(&data).then(|| reset_messages(&self.m_channels, self.m_master_volume))
+    }
+
+    // 在当前的采样时钟松开文件通道上的音符
+    // 实时演奏在live_channel选出的通道上时不受影响 和文件共用通道时会一起松开
+    fn release_notes(&self) {
+        let mut scheduler = self.m_scheduler.lock().unwrap();
+        let clock = scheduler.clock();
+        for channel in self.m_channels.iter() {
+            let all_notes_off = [0xB0 | channel.index(), 0x7B, 0];
+            scheduler.schedule(clock, MidiMessage::from_live(&all_notes_off).unwrap());
+        }
+    }
+}
+
+// 循环的小节从1开始 不能超出曲子的长度
+fn check_loop(start_bar: u32, end_bar: u32, bar_count: u32) -> Result<(), Box<dyn Error>> {
+    if start_bar.min(end_bar) == 0 || start_bar.max(end_bar) > bar_count {
+        return Err(
+            format!("错误的循环范围: {start_bar}-{end_bar} (曲子共{bar_count}小节)").into(),
+        );
+    }
+    Ok(())
+}
+
+// 实时演奏用的通道 选一个文件没有用到的 不用打击乐通道
+// 文件用满了所有通道时返回None 只能和文件共用通道
+pub fn live_channel(file_channels: &[MidiChannel]) -> Option<MidiChannel> {
+    (0..16)
+        .filter_map(MidiChannel::new)
+        .find(|channel| *channel != MidiChannel::DRUM && !file_channels.contains(channel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_channel_not_used_by_file() {
+        let channels = |indexes: &[u8]| -> Vec<MidiChannel> {
+            indexes
+                .iter()
+                .filter_map(|index| MidiChannel::new(*index))
+                .collect()
+        };
+        assert_eq!(live_channel(&[]), MidiChannel::new(0));
+        assert_eq!(live_channel(&channels(&[0, 1])), MidiChannel::new(2));
+        let all_but_drum: Vec<u8> = (0..16).filter(|index| *index != 9).collect();
+        assert_eq!(live_channel(&channels(&all_but_drum)), None);
+    }
+
+    #[test]
+    fn loop_range() {
+        assert!(check_loop(1, 4, 4).is_ok());
+        assert!(check_loop(4, 1, 4).is_ok());
+        assert!(check_loop(0, 2, 4).is_err());
+        assert!(check_loop(3, 5, 4).is_err());
+        assert!(check_loop(1, u32::MAX, 4).is_err());
+    }
+}
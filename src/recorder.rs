@@ -0,0 +1,94 @@
+use crate::midi_format::{
+    header::{Header, TimeDivision},
+    midi_message::{MetaEvent, MidiMessage},
+    tracks::{MidiTrack, Tracks},
+    MidiFile,
+};
+
+pub struct RecordOptions {
+    pub tempo: u32,  // 每个四分音符的微秒数
+    pub ppq: u16,    // 每个四分音符的tick数
+    pub format: u16, // 0: 所有事件放在一个音轨 1: 速度信息和演奏分成两个音轨
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        RecordOptions {
+            tempo: 500_000,
+            ppq: 480,
+            format: 0,
+        }
+    }
+}
+
+// 记录实时输入的消息 停止录音时再按选定的速度换算成tick
+#[derive(Default)]
+pub struct Recorder {
+    m_events: Vec<(u64, MidiMessage)>, // (midir回调里的时间戳 单位微秒, 消息)
+}
+
+impl Recorder {
+    pub fn record(&mut self, stamp: u64, midi_message: MidiMessage) {
+        self.m_events.push((stamp, midi_message));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.m_events.is_empty()
+    }
+
+    // 第一个事件作为曲子的开始
+    pub fn to_midi_file(&self, options: &RecordOptions) -> MidiFile {
+        let start = self.m_events.first().map_or(0, |(stamp, _)| *stamp);
+        let performance: Vec<(u64, MidiMessage)> = self
+            .m_events
+            .iter()
+            .map(|(stamp, midi_message)| {
+                let tick = (stamp - start) * options.ppq as u64 / options.tempo.max(1) as u64;
+                let mut midi_message = midi_message.clone();
+                midi_message.m_running_status = false;
+                (tick, midi_message)
+            })
+            .collect();
+        let conductor = vec![
+            (
+                0,
+                MidiMessage::from_meta(
+                    0,
+                    MetaEvent::SetTempo {
+                        tempo: options.tempo,
+                    },
+                ),
+            ),
+            (
+                0,
+                MidiMessage::from_meta(
+                    0,
+                    MetaEvent::TimeSignature {
+                        numerator: 4,
+                        denominator_power: 2,
+                        clocks_per_click: 24,
+                        thirty_seconds_per_quarter: 8,
+                    },
+                ),
+            ),
+        ];
+        let tracks = match options.format {
+            0 => vec![[conductor, performance].concat()],
+            _ => vec![conductor, performance],
+        };
+        let tracks: Vec<MidiTrack> = tracks.into_iter().map(MidiTrack::from_absolute).collect();
+        MidiFile {
+            header: Header {
+                m_magic: *b"MThd",
+                m_header_size: 6,
+                m_format: options.format.min(1),
+                m_num_tracks: tracks.len() as u16,
+                m_time_division: TimeDivision::TicksPerQuarter(options.ppq),
+                m_extra: Vec::new(),
+            },
+            tracks: Tracks(tracks),
+            unknown_chunks: Vec::new(),
+            embedded_soundfont: None,
+        }
+    }
+}
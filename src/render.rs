@@ -0,0 +1,62 @@
+use std::{error::Error, fs::File, io::BufWriter, path::Path, time::Duration};
+
+use rustysynth::Synthesizer;
+
+use crate::{
+    config::CONFIG,
+    midi_format::{
+        sequencer::merge_tracks,
+        tempo_map::{mul_div, TempoMap},
+        MidiFile,
+    },
+    scheduler::Scheduler,
+    wav::{WavFormat, WavWriter},
+};
+
+pub struct RenderOptions {
+    pub format: WavFormat,
+    pub tail: Duration, // 最后一个事件之后继续渲染的时间 给混响留出衰减的时间
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            format: WavFormat::Pcm16,
+            tail: Duration::from_secs(2),
+        }
+    }
+}
+
+// 不经过声卡 按合并后的事件时间线把整首曲子渲染到WAV文件
+// 和实时播放用同一个调度器 输出的采样完全一样
+pub fn render_to_wav(
+    midi_file: &MidiFile,
+    synthesizer: &mut Synthesizer,
+    path: &Path,
+    options: &RenderOptions,
+) -> Result<(), Box<dyn Error>> {
+    let sample_rate = synthesizer.get_sample_rate() as u64;
+    let file = BufWriter::new(File::create(path)?);
+    let mut wav_writer = WavWriter::new(file, sample_rate as u32, 2, options.format)?;
+    let block_size = CONFIG.channel_sample_count as usize;
+    let mut left: Vec<f32> = vec![0f32; block_size];
+    let mut right: Vec<f32> = vec![0f32; block_size];
+
+    let tempo_map = TempoMap::new(midi_file);
+    let mut scheduler = Scheduler::default();
+    let mut last_frame: u64 = 0;
+    for sequenced_event in merge_tracks(midi_file) {
+        let micros = tempo_map.ticks_to_micros(sequenced_event.m_tick);
+        last_frame = mul_div(micros, sample_rate, 1_000_000);
+        scheduler.schedule(last_frame, sequenced_event.m_message.clone());
+    }
+    let tail_micros = options.tail.as_micros().min(u64::MAX as u128) as u64;
+    let end_frame = last_frame.saturating_add(mul_div(tail_micros, sample_rate, 1_000_000));
+    while scheduler.clock() < end_frame {
+        let size = (end_frame - scheduler.clock()).min(block_size as u64) as usize;
+        scheduler.render(synthesizer, &mut left[..size], &mut right[..size]);
+        wav_writer.write_stereo(&left[..size], &right[..size])?;
+    }
+    wav_writer.finish()?;
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::VecDeque,
+    mem::take,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use rustysynth::Synthesizer;
+
+use crate::{
+    midi_format::midi_message::{MidiMessage, SysExAssembler},
+    synthesizers::process_event,
+};
+
+pub type SharedScheduler = Arc<Mutex<Scheduler>>;
+
+// 在第m_frame帧发给合成器的消息
+#[derive(Debug, Clone)]
+pub struct ScheduledEvent {
+    pub m_frame: u64,
+    pub m_message: MidiMessage,
+}
+
+// 渲染时按顺序要做的事 先处理到时间的事件 再渲染到下一个事件之前
+#[derive(Debug)]
+pub enum RenderStep {
+    Event { message: MidiMessage },
+    Frames { range: Range<usize> }, // 在这一次渲染的缓冲区里的范围
+}
+
+// 带时间戳的事件队列和采样时钟
+// 渲染时在事件的位置把一块切开 事件落在准确的采样上 不受线程调度的影响
+// 合成器内部按自己的块大小(默认64帧)更新声音 这是事件生效的最小间隔
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    m_queue: VecDeque<ScheduledEvent>, // 按m_frame排序
+    m_clock: u64,                      // 已经渲染的帧数
+    m_sysex_assembler: SysExAssembler,
+}
+
+impl Scheduler {
+    pub fn clock(&self) -> u64 {
+        self.m_clock
+    }
+
+    // 同一帧上的事件按加入的顺序处理 已经过去的帧在下一次渲染开始时马上处理
+    pub fn schedule(&mut self, frame: u64, midi_message: MidiMessage) {
+        let event = ScheduledEvent {
+            m_frame: frame,
+            m_message: midi_message,
+        };
+        if self.m_queue.back().is_none_or(|last| last.m_frame <= frame) {
+            self.m_queue.push_back(event);
+        } else {
+            let index = self
+                .m_queue
+                .partition_point(|queued| queued.m_frame <= frame);
+            self.m_queue.insert(index, event);
+        }
+    }
+
+    // 丢掉还没到时间的事件 时钟不变
+    // 已经到时间(不晚于时钟)的事件保留 下一次渲染开始时照常处理
+    pub fn clear(&mut self) {
+        let clock = self.m_clock;
+        self.m_queue.retain(|event| event.m_frame <= clock);
+    }
+
+    // 渲染left.len()帧 中间到时间的事件在对应的帧上发给合成器
+    pub fn render(&mut self, synthesizer: &mut Synthesizer, left: &mut [f32], right: &mut [f32]) {
+        let mut sysex_assembler = take(&mut self.m_sysex_assembler);
+        self.advance(left.len(), |step| match step {
+            RenderStep::Event { message } => {
+                process_event(synthesizer, &message, &mut sysex_assembler)
+            }
+            RenderStep::Frames { range } => {
+                synthesizer.render(&mut left[range.clone()], &mut right[range])
+            }
+        });
+        self.m_sysex_assembler = sysex_assembler;
+    }
+
+    // 把时钟推进frames帧 在事件的位置把这一段切开 按顺序交给on_step
+    pub fn advance(&mut self, frames: usize, mut on_step: impl FnMut(RenderStep)) {
+        let start = self.m_clock;
+        let end = start + frames as u64;
+        while self.m_clock < end {
+            while let Some(event) = self
+                .m_queue
+                .pop_front_if(|event| event.m_frame <= self.m_clock)
+            {
+                on_step(RenderStep::Event {
+                    message: event.m_message,
+                });
+            }
+            let next = self
+                .m_queue
+                .front()
+                .map_or(end, |event| event.m_frame.min(end));
+            on_step(RenderStep::Frames {
+                range: (self.m_clock - start) as usize..(next - start) as usize,
+            });
+            self.m_clock = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 用Program Change的音色号区分消息
+    fn message(program: u8) -> MidiMessage {
+        MidiMessage::from_live(&[0xC0, program]).unwrap()
+    }
+
+    fn steps(scheduler: &mut Scheduler, frames: usize) -> Vec<String> {
+        let mut steps = Vec::new();
+        scheduler.advance(frames, |step| {
+            steps.push(match step {
+                RenderStep::Event { message } => format!("{}", message.get_raw()[1]),
+                RenderStep::Frames { range } => format!("{}..{}", range.start, range.end),
+            })
+        });
+        steps
+    }
+
+    #[test]
+    fn split_at_event_frames() {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule(0, message(1));
+        scheduler.schedule(100, message(2));
+        scheduler.schedule(300, message(3));
+        assert_eq!(steps(&mut scheduler, 256), ["1", "0..100", "2", "100..256"]);
+        assert_eq!(scheduler.clock(), 256);
+        // 下一块里的位置从0开始算
+        assert_eq!(steps(&mut scheduler, 256), ["0..44", "3", "44..256"]);
+        assert_eq!(steps(&mut scheduler, 10), ["0..10"]);
+    }
+
+    // 同一帧上按加入的顺序 已经过去的帧在下一块开始时处理
+    #[test]
+    fn same_frame_order() {
+        let mut scheduler = Scheduler::default();
+        scheduler.schedule(50, message(1));
+        scheduler.schedule(20, message(2));
+        scheduler.schedule(50, message(3));
+        scheduler.schedule(20, message(4));
+        assert_eq!(
+            steps(&mut scheduler, 64),
+            ["0..20", "2", "4", "20..50", "1", "3", "50..64"]
+        );
+        scheduler.schedule(10, message(5));
+        scheduler.schedule(64, message(6));
+        assert_eq!(steps(&mut scheduler, 64), ["5", "6", "0..64"]);
+    }
+
+    // 只丢掉还没到时间的事件
+    #[test]
+    fn clear() {
+        let mut scheduler = Scheduler::default();
+        assert_eq!(steps(&mut scheduler, 100), ["0..100"]);
+        scheduler.schedule(90, message(1));
+        scheduler.schedule(100, message(2));
+        scheduler.schedule(101, message(3));
+        scheduler.clear();
+        assert_eq!(scheduler.clock(), 100);
+        assert_eq!(steps(&mut scheduler, 10), ["1", "2", "0..10"]);
+    }
+}
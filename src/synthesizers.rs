@@ -1,23 +1,187 @@
 use std::error::Error;
 use std::fs::File;
-use std::io;
+use std::io::{self, Cursor};
 use rustysynth::{SoundFont, Synthesizer, SynthesizerSettings};
 use std::sync::{Arc, Mutex};
 
 use crate::config::CONFIG;
+use crate::midi_format::{
+    base::{MidiChannel, MidiStatusByte},
+    midi_message::{Event, MessageEvent, MidiMessage, SysExAssembler},
+};
 
-const SF2_PATH: &str = "sf2/TimGM6mb.sf2";
+const MASTER_VOLUME: f32 = 12f32;
 
-pub fn init_synthesizers() -> Result<Arc<Mutex<Synthesizer>>, Box<dyn Error>> {
-    let mut sf2 = open_sf2(SF2_PATH)?;
-    let sound_font = Arc::new(SoundFont::new(&mut sf2)?);
+pub type SharedSynthesizer = Arc<Mutex<Synthesizer>>;
+
+// embedded_sf2是MIDI文件(RMID)自带的音色库 有的话优先使用
+pub fn init_synthesizers(embedded_sf2: Option<&[u8]>) -> Result<SharedSynthesizer, Box<dyn Error>> {
+    let sound_font = match embedded_sf2 {
+        Some(sf2) => SoundFont::new(&mut Cursor::new(sf2))
+            .map_err(|err| format!("无法读取文件自带的音色库: {err}"))?,
+        None => {
+            let mut sf2 = open_sf2(&CONFIG.sf2_path)
+                .map_err(|err| format!("无法打开音色库 {}: {err}", CONFIG.sf2_path))?;
+            SoundFont::new(&mut sf2)?
+        }
+    };
+    let sound_font = Arc::new(sound_font);
     let settings = SynthesizerSettings::new(CONFIG.sample_rate as i32);
     let synthesizer: Synthesizer =  Synthesizer::new(&sound_font, &settings)?;
     let synthesizer: Arc<Mutex<Synthesizer>> = Arc::new(Mutex::new(synthesizer));
-    synthesizer.lock().unwrap().set_master_volume(12f32);
+    synthesizer.lock().unwrap().set_master_volume(MASTER_VOLUME);
+    set_pitch_bend_range_all(&mut synthesizer.lock().unwrap(), CONFIG.pitch_bend_range);
     Ok(synthesizer)
 }
 
+// 通过RPN 0设置通道的弯音范围 设置完后把RPN恢复成null 避免后续的Data Entry误改
+pub fn set_pitch_bend_range(synthesizer: &mut Synthesizer, channel: i32, semitones: u8, cents: u8) {
+    for (controller, value) in [
+        (0x65, 0x00),
+        (0x64, 0x00),
+        (0x06, semitones),
+        (0x26, cents),
+        (0x65, 0x7F),
+        (0x64, 0x7F),
+    ] {
+        synthesizer.process_midi_message(channel, 0xB0, controller, value as i32);
+    }
+}
+
+fn set_pitch_bend_range_all(synthesizer: &mut Synthesizer, semitones: u8) {
+    for channel in 0..16 {
+        set_pitch_bend_range(synthesizer, channel, semitones, 0);
+    }
+}
+
+// 处理一个事件 通道消息发到对应通道 sysex拼完整后再处理 meta事件不需要合成器处理
+pub fn process_event(
+    synthesizer: &mut Synthesizer,
+    midi_message: &MidiMessage,
+    sysex_assembler: &mut SysExAssembler,
+) {
+    match &midi_message.m_ment_event {
+        Event::Midi { .. } => send_message(synthesizer, midi_message),
+        Event::SysEx { sysex } => {
+            if let Some(data) = sysex_assembler.push(sysex) {
+                apply_sysex(synthesizer, &data);
+            }
+        }
+        Event::Meta { .. } | Event::None => (),
+    }
+}
+
+// 把通道消息发送到它自己的通道上
+pub fn send_message(synthesizer: &mut Synthesizer, midi_message: &MidiMessage) {
+    let (Some(channel), Event::Midi { message }) =
+        (midi_message.channel(), &midi_message.m_ment_event)
+    else {
+        return;
+    };
+    let channel = channel.index() as i32;
+    match message {
+        MessageEvent::NoteOn { key, velocity } => {
+            synthesizer.note_on(channel, key.bits() as i32, velocity.bits() as i32)
+        }
+        MessageEvent::NoteOff { key, .. } => synthesizer.note_off(channel, key.bits() as i32),
+        // 控制器 音色 弯音 触后交给合成器自己处理
+        _ => {
+            let command = midi_message
+                .m_status
+                .intersection(MidiStatusByte::command | MidiStatusByte::flag);
+            let (data1, data2) = message.data();
+            synthesizer.process_midi_message(
+                channel,
+                command.bits() as i32,
+                data1 as i32,
+                data2 as i32,
+            )
+        }
+    }
+}
+
+// 处理完整的sysex消息 (F0 ... F7)
+// 合成器只认识复位和主音量 其他厂商消息直接忽略
+pub fn apply_sysex(synthesizer: &mut Synthesizer, message: &[u8]) {
+    match message {
+        _ if is_system_reset(message) => reset_synthesizer(synthesizer),
+        // GM Master Volume: F0 7F <dev> 04 01 <lsb> <msb> F7
+        [0xF0, 0x7F, _, 0x04, 0x01, lsb, msb, 0xF7] => {
+            let volume = ((*msb as u16) << 7 | *lsb as u16) as f32 / 16383f32;
+            synthesizer.set_master_volume(MASTER_VOLUME * volume);
+        }
+        _ => (),
+    }
+}
+
+// 复位所有通道的sysex
+pub fn is_system_reset(message: &[u8]) -> bool {
+    matches!(
+        message,
+        // GM System On / Off, GM2 System On: F0 7E <dev> 09 01|02|03 F7
+        [0xF0, 0x7E, _, 0x09, 0x01..=0x03, 0xF7]
+        // GS Reset: F0 41 <dev> 42 12 40 00 7F 00 41 F7
+        | [0xF0, 0x41, _, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, 0x41, 0xF7]
+        // XG System On: F0 43 1n 4C 00 00 7E 00 F7
+        | [0xF0, 0x43, 0x10..=0x1F, 0x4C, 0x00, 0x00, 0x7E, 0x00, 0xF7]
+    )
+}
+
+// 改变整个合成器音量的sysex
+pub fn is_master_volume(message: &[u8]) -> bool {
+    matches!(message, [0xF0, 0x7F, _, 0x04, 0x01, _, _, 0xF7])
+}
+
+// 只把channels恢复到刚初始化时的状态 效果和reset_synthesizer一样 其他通道不受影响
+// 主音量是整个合成器共用的 master_volume为true时才恢复
+// 用消息表示 可以和其他事件一起排进调度器
+pub fn reset_messages(channels: &[MidiChannel], master_volume: bool) -> Vec<MidiMessage> {
+    let mut raw_messages: Vec<Vec<u8>> = Vec::new();
+    if master_volume {
+        // GM Master Volume 最大 对应MASTER_VOLUME
+        raw_messages.push(vec![0xF0, 0x7F, 0x7F, 0x04, 0x01, 0x7F, 0x7F, 0xF7]);
+    }
+    for channel in channels.iter() {
+        let status = 0xB0 | channel.index();
+        for (controller, value) in [
+            (0x78, 0),   // All Sound Off
+            (0x79, 0),   // Reset All Controllers 调制 表情 延音踏板 弯音 RPN
+            (0x00, 0),   // Bank Select
+            (0x07, 100), // 音量
+            (0x27, 0),
+            (0x0A, 64), // 声像
+            (0x2A, 0),
+            (0x5B, 40),   // 混响
+            (0x5D, 0),    // 合唱
+            (0x65, 0x00), // RPN 1 微调 居中
+            (0x64, 0x01),
+            (0x06, 0x40),
+            (0x26, 0x00),
+            (0x64, 0x02), // RPN 2 粗调 居中
+            (0x06, 0x40),
+            (0x64, 0x00), // RPN 0 弯音范围
+            (0x06, CONFIG.pitch_bend_range),
+            (0x26, 0x00),
+            (0x65, 0x7F), // RPN恢复成null
+            (0x64, 0x7F),
+        ] {
+            raw_messages.push(vec![status, controller, value]);
+        }
+        raw_messages.push(vec![0xC0 | channel.index(), 0]); // Program Change
+    }
+    raw_messages
+        .iter()
+        .map(|raw_data| MidiMessage::from_live(raw_data).unwrap())
+        .collect()
+}
+
+// 关掉所有声音 恢复到刚初始化时的状态
+pub fn reset_synthesizer(synthesizer: &mut Synthesizer) {
+    synthesizer.reset();
+    synthesizer.set_master_volume(MASTER_VOLUME);
+    set_pitch_bend_range_all(synthesizer, CONFIG.pitch_bend_range);
+}
+
 fn open_sf2(path: &str) -> io::Result<File> {
     File::open(path)
 }
\ No newline at end of file
@@ -0,0 +1,174 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+// 输出的采样格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavFormat {
+    Pcm16,
+    Pcm24,
+    Float32,
+}
+
+impl WavFormat {
+    fn bytes_per_sample(&self) -> u16 {
+        match self {
+            WavFormat::Pcm16 => 2,
+            WavFormat::Pcm24 => 3,
+            WavFormat::Float32 => 4,
+        }
+    }
+
+    fn format_tag(&self) -> u16 {
+        match self {
+            WavFormat::Pcm16 | WavFormat::Pcm24 => WAVE_FORMAT_PCM,
+            WavFormat::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+        }
+    }
+}
+
+// 边渲染边写的WAV文件 结束时回到开头补上各个chunk的大小
+pub struct WavWriter<W: Write + Seek> {
+    m_writer: W,
+    m_format: WavFormat,
+    m_channels: u16,
+    m_frames: u32,              // 已经写入的帧数
+    m_fact_offset: Option<u64>, // float格式的fact chunk中帧数的位置
+    m_data_offset: u64,         // 采样数据开始的位置
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(
+        mut writer: W,
+        sample_rate: u32,
+        channels: u16,
+        format: WavFormat,
+    ) -> io::Result<WavWriter<W>> {
+        let block_align = channels * format.bytes_per_sample();
+        let is_float = format == WavFormat::Float32;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?; // 结束时补上
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&(if is_float { 18u32 } else { 16u32 }).to_le_bytes())?;
+        writer.write_all(&format.format_tag().to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&(format.bytes_per_sample() * 8).to_le_bytes())?;
+        let mut fact_offset = None;
+        if is_float {
+            // 非PCM格式需要cbSize和fact chunk
+            writer.write_all(&0u16.to_le_bytes())?;
+            writer.write_all(b"fact")?;
+            writer.write_all(&4u32.to_le_bytes())?;
+            fact_offset = Some(writer.stream_position()?);
+            writer.write_all(&0u32.to_le_bytes())?;
+        }
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        let data_offset = writer.stream_position()?;
+        Ok(WavWriter {
+            m_writer: writer,
+            m_format: format,
+            m_channels: channels,
+            m_frames: 0,
+            m_fact_offset: fact_offset,
+            m_data_offset: data_offset,
+        })
+    }
+
+    // 写入交错排列的采样 长度需要是声道数的整数倍
+    // RIFF的长度字段只有32位 文件超过4GiB时报错
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let file_size = self.m_data_offset
+            + self.data_size()
+            + samples.len() as u64 * self.m_format.bytes_per_sample() as u64;
+        if file_size - 8 > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "WAV文件超过4GiB",
+            ));
+        }
+        let mut buffer: Vec<u8> =
+            Vec::with_capacity(samples.len() * self.m_format.bytes_per_sample() as usize);
+        for sample in samples.iter() {
+            let sample = sample.clamp(-1f32, 1f32);
+            match self.m_format {
+                WavFormat::Pcm16 => buffer
+                    .extend_from_slice(&((sample * i16::MAX as f32).round() as i16).to_le_bytes()),
+                WavFormat::Pcm24 => {
+                    let value = (sample * 8_388_607f32).round() as i32;
+                    buffer.extend_from_slice(&value.to_le_bytes()[..3])
+                }
+                WavFormat::Float32 => buffer.extend_from_slice(&sample.to_le_bytes()),
+            }
+        }
+        self.m_writer.write_all(&buffer)?;
+        self.m_frames += (samples.len() / self.m_channels as usize) as u32;
+        Ok(())
+    }
+
+    // 写入左右两个声道
+    pub fn write_stereo(&mut self, left: &[f32], right: &[f32]) -> io::Result<()> {
+        let samples: Vec<f32> = left
+            .iter()
+            .zip(right.iter())
+            .flat_map(|(left, right)| [*left, *right])
+            .collect();
+        self.write_samples(&samples)
+    }
+
+    // 已经写入的采样数据的字节数
+    fn data_size(&self) -> u64 {
+        self.m_frames as u64 * self.m_channels as u64 * self.m_format.bytes_per_sample() as u64
+    }
+
+    // write_samples保证了文件不超过4GiB 这里的大小都能放进u32
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.data_size() as u32;
+        let file_size = self.m_writer.stream_position()?;
+        self.m_writer.seek(SeekFrom::Start(4))?;
+        self.m_writer
+            .write_all(&((file_size - 8) as u32).to_le_bytes())?;
+        if let Some(fact_offset) = self.m_fact_offset {
+            self.m_writer.seek(SeekFrom::Start(fact_offset))?;
+            self.m_writer.write_all(&self.m_frames.to_le_bytes())?;
+        }
+        self.m_writer
+            .seek(SeekFrom::Start(self.m_data_offset - 4))?;
+        self.m_writer.write_all(&data_size.to_le_bytes())?;
+        self.m_writer.seek(SeekFrom::Start(file_size))?;
+        self.m_writer.flush()?;
+        Ok(self.m_writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn chunk_sizes() {
+        let mut wav_writer =
+            WavWriter::new(Cursor::new(Vec::new()), 48000, 2, WavFormat::Pcm16).unwrap();
+        wav_writer.write_stereo(&[0f32; 10], &[0f32; 10]).unwrap();
+        let raw_data = wav_writer.finish().unwrap().into_inner();
+        assert_eq!(raw_data.len(), 44 + 40);
+        assert_eq!(raw_data[4..8], ((44 + 40 - 8) as u32).to_le_bytes());
+        assert_eq!(raw_data[40..44], 40u32.to_le_bytes());
+    }
+
+    // 不真的写4GiB 直接把已写入的帧数设到上限附近
+    #[test]
+    fn larger_than_4gib() {
+        let mut wav_writer =
+            WavWriter::new(Cursor::new(Vec::new()), 48000, 2, WavFormat::Pcm16).unwrap();
+        wav_writer.m_frames = ((u32::MAX as u64 + 8 - 44) / 4) as u32;
+        assert!(wav_writer.write_stereo(&[], &[]).is_ok());
+        assert!(wav_writer.write_stereo(&[0f32], &[0f32]).is_err());
+    }
+}